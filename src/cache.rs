@@ -0,0 +1,235 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use rodio::{Decoder, Source};
+
+/// A whole clip file, decoded into memory
+#[derive(Clone)]
+pub struct DecodedClip {
+    samples: Arc<[i16]>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl DecodedClip {
+    pub fn decode(path: &str) -> anyhow::Result<Self> {
+        let decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+        Ok(DecodedClip {
+            samples: decoder.collect(),
+            channels,
+            sample_rate,
+        })
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of_val(&*self.samples)
+    }
+
+    /// A new playback of this clip, sharing the decoded samples
+    pub fn play(&self) -> CachedSource {
+        CachedSource {
+            clip: self.clone(),
+            position: 0,
+        }
+    }
+}
+
+/// Plays a [DecodedClip] from memory
+pub struct CachedSource {
+    clip: DecodedClip,
+    position: usize,
+}
+
+impl Iterator for CachedSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.clip.samples.get(self.position)?;
+        self.position += 1;
+        Some(*sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.clip.samples.len() - self.position;
+        (left, Some(left))
+    }
+}
+
+impl Source for CachedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.clip.samples.len() - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.clip.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.clip.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.clip.samples.len() / self.clip.channels.max(1) as usize;
+        Some(Duration::from_secs_f64(
+            frames as f64 / self.clip.sample_rate as f64,
+        ))
+    }
+}
+
+struct CacheEntry {
+    clip: DecodedClip,
+    last_used: u64,
+}
+
+/// Decoded clips kept in memory (keyed by file path), so that they can start without
+/// opening and decoding the file. Once the memory budget is used up, the least recently
+/// played clips are evicted.
+pub struct ClipCache {
+    entries: HashMap<String, CacheEntry>,
+    budget_bytes: usize,
+    used_bytes: usize,
+    /// Incremented on every use, to find the least recently used entry
+    uses: u64,
+    /// Preload every clip, not only those marked `preload`
+    preload_all: bool,
+    /// Files found to be larger than the whole budget, which are always streamed
+    oversize: HashSet<String>,
+}
+
+impl ClipCache {
+    pub fn new(budget_bytes: usize, preload_all: bool) -> Self {
+        ClipCache {
+            entries: HashMap::new(),
+            budget_bytes,
+            used_bytes: 0,
+            uses: 0,
+            preload_all,
+            oversize: HashSet::new(),
+        }
+    }
+
+    pub fn should_preload(&self, clip_preload: bool) -> bool {
+        clip_preload || self.preload_all
+    }
+
+    /// Decode the file (if not already cached) and keep it in memory
+    pub fn load(&mut self, path: &str) -> anyhow::Result<DecodedClip> {
+        self.uses += 1;
+        if let Some(entry) = self.entries.get_mut(path) {
+            entry.last_used = self.uses;
+            return Ok(entry.clip.clone());
+        }
+        let clip = DecodedClip::decode(path)?;
+        self.insert(path, clip.clone());
+        Ok(clip)
+    }
+
+    /// Play from memory if the file is cached, or if it should be (in which case it is
+    /// decoded now, and kept for next time); otherwise None, to be streamed from disk
+    pub fn source(&mut self, path: &str, preload: bool) -> Option<CachedSource> {
+        if self.oversize.contains(path)
+            || (!self.should_preload(preload) && !self.entries.contains_key(path))
+        {
+            return None;
+        }
+        match self.load(path) {
+            // Just found to be too big to keep; stream it, as it will be from now on
+            Ok(_) if self.oversize.contains(path) => None,
+            Ok(clip) => Some(clip.play()),
+            Err(e) => {
+                warn!("Could not decode \"{}\" into memory: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, path: &str, clip: DecodedClip) {
+        let size = clip.size_bytes();
+        if size > self.budget_bytes {
+            warn!(
+                "\"{}\" ({} KB) is larger than the whole preload budget; it will be streamed from disk",
+                path,
+                size / 1024
+            );
+            self.oversize.insert(String::from(path));
+            return;
+        }
+        while self.used_bytes + size > self.budget_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                info!("Preload budget full; evicted \"{}\"", oldest);
+                self.used_bytes -= entry.clip.size_bytes();
+            }
+        }
+        debug!("Cached \"{}\" ({} KB)", path, size / 1024);
+        self.used_bytes += size;
+        self.entries.insert(
+            String::from(path),
+            CacheEntry {
+                clip,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+#[cfg(test)]
+fn silent_clip(samples: usize) -> DecodedClip {
+    DecodedClip {
+        samples: vec![0; samples].into(),
+        channels: 1,
+        sample_rate: 1000,
+    }
+}
+
+#[test]
+fn least_recently_used_is_evicted() {
+    // Room for two clips of 100 samples (200 bytes each)
+    let mut cache = ClipCache::new(400, false);
+    cache.insert("a", silent_clip(100));
+    cache.uses += 1;
+    cache.insert("b", silent_clip(100));
+    assert!(cache.load("a").is_ok());
+    cache.insert("c", silent_clip(100));
+    assert!(cache.entries.contains_key("a"));
+    assert!(!cache.entries.contains_key("b"));
+    assert_eq!(cache.used_bytes(), 400);
+}
+
+#[test]
+fn cached_source_plays_whole_clip() {
+    let clip = silent_clip(500);
+    let source = clip.play();
+    // Samples are shared, not copied
+    assert_eq!(Arc::strong_count(&clip.samples), 2);
+    assert_eq!(source.total_duration(), Some(Duration::from_millis(500)));
+    assert_eq!(source.count(), 500);
+}
+
+#[test]
+fn oversize_clip_is_streamed_without_decoding_again() {
+    let mut cache = ClipCache::new(100, true);
+    cache.insert("big", silent_clip(100));
+    assert_eq!(cache.used_bytes(), 0);
+    // Never decoded again (the file does not even exist)
+    assert!(cache.source("big", true).is_none());
+    assert_eq!(cache.uses, 0);
+}
@@ -0,0 +1,50 @@
+use std::{
+    cell::Cell,
+    time::{Duration, SystemTime},
+};
+
+thread_local! {
+    /// Set only when rendering offline; otherwise the system clock is used
+    static VIRTUAL_NOW: Cell<Option<SystemTime>> = const { Cell::new(None) };
+}
+
+/// Current time as seen by the Model: normally the system clock, but a virtual clock
+/// (which only moves when told to) when rendering offline. The virtual clock applies to
+/// the current thread only.
+pub fn now() -> SystemTime {
+    VIRTUAL_NOW
+        .with(|t| t.get())
+        .unwrap_or_else(SystemTime::now)
+}
+
+/// Time since an earlier reading of [now]
+pub fn since(earlier: SystemTime) -> Duration {
+    now().duration_since(earlier).unwrap_or_default()
+}
+
+/// Local date and time, for the time-of-day schedule
+pub fn local_now() -> chrono::NaiveDateTime {
+    chrono::DateTime::<chrono::Local>::from(now()).naive_local()
+}
+
+/// Switch the current thread to a virtual clock, set to the given time
+pub fn set_virtual(time: SystemTime) {
+    VIRTUAL_NOW.with(|t| t.set(Some(time)));
+}
+
+/// Switch the current thread back to the system clock
+pub fn use_system() {
+    VIRTUAL_NOW.with(|t| t.set(None));
+}
+
+#[test]
+fn virtual_clock_only_moves_when_set() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    set_virtual(start);
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(now(), start);
+    set_virtual(start + Duration::from_millis(250));
+    assert_eq!(since(start), Duration::from_millis(250));
+    use_system();
+    assert!(since(start) > Duration::from_secs(1000));
+}
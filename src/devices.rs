@@ -0,0 +1,296 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use log::*;
+use regex::Regex;
+use rodio::{
+    cpal::{self, traits::HostTrait, SupportedBufferSize},
+    DeviceTrait,
+};
+use serde::Serialize;
+
+/// One way of choosing an output device, as given to `--output.device`
+#[derive(Debug)]
+pub enum DeviceMatcher {
+    /// "default": the host's default device
+    Default,
+    /// "#2" or "2": position in the device list
+    Index(usize),
+    /// "/pattern/": regular expression
+    Pattern(Regex),
+    /// Anything else: the exact name (ignoring case) or, failing that, part of it
+    Name(String),
+}
+
+impl FromStr for DeviceMatcher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("default") {
+            return Ok(DeviceMatcher::Default);
+        }
+        if let Ok(index) = s.strip_prefix('#').unwrap_or(s).parse::<usize>() {
+            return Ok(DeviceMatcher::Index(index));
+        }
+        if let Some(pattern) = s.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+            return Regex::new(pattern)
+                .map(DeviceMatcher::Pattern)
+                .map_err(|e| anyhow!("Invalid device pattern \"{}\": {}", pattern, e));
+        }
+        Ok(DeviceMatcher::Name(s.to_lowercase()))
+    }
+}
+
+impl DeviceMatcher {
+    /// Index of the matching device (if any) among the given names
+    fn find(&self, names: &[String], default_index: Option<usize>) -> Option<usize> {
+        match self {
+            DeviceMatcher::Default => default_index,
+            DeviceMatcher::Index(index) => (*index < names.len()).then_some(*index),
+            DeviceMatcher::Pattern(regex) => names.iter().position(|n| regex.is_match(n)),
+            DeviceMatcher::Name(name) => names
+                .iter()
+                .position(|n| n.to_lowercase() == *name)
+                .or_else(|| names.iter().position(|n| n.to_lowercase().contains(name))),
+        }
+    }
+}
+
+/// A device option (as for `--output.device`) matching only the device with exactly
+/// this name, e.g. one picked from a list, even if the name looks like an index, a
+/// pattern or "default"
+pub fn exact_device_spec(name: &str) -> String {
+    format!("/^{}$/", regex::escape(name))
+}
+
+/// Try each matcher in turn (e.g. "MOTU, then Focusrite, then default"), returning the
+/// index of the first device found
+pub fn pick_device(
+    matchers: &[DeviceMatcher],
+    names: &[String],
+    default_index: Option<usize>,
+) -> Option<usize> {
+    matchers.iter().find_map(|m| {
+        let found = m.find(names, default_index);
+        if found.is_none() {
+            info!("No output device matches {:?}; trying the next option", m);
+        }
+        found
+    })
+}
+
+/// Choose an output device of the default host, according to the `--output.device`
+/// options (or the default device, if none were given)
+pub fn select_device(specs: &[String]) -> anyhow::Result<rodio::Device> {
+    let host = cpal::default_host();
+    if specs.is_empty() {
+        return host.default_output_device().ok_or(anyhow!(
+            "No default output device (use --output.null if there is none)"
+        ));
+    }
+    let matchers = specs
+        .iter()
+        .map(|s| s.parse())
+        .collect::<anyhow::Result<Vec<DeviceMatcher>>>()?;
+
+    let mut devices: Vec<rodio::Device> = host.output_devices()?.collect();
+    let names: Vec<String> = devices
+        .iter()
+        .map(|d| d.name().unwrap_or(String::from("unknown")))
+        .collect();
+    for (i, name) in names.iter().enumerate() {
+        debug!("Device #{}: \"{}\"", i, name);
+    }
+    let default_name = host.default_output_device().and_then(|d| d.name().ok());
+    let default_index = names.iter().position(|n| Some(n) == default_name.as_ref());
+
+    match pick_device(&matchers, &names, default_index) {
+        Some(index) => Ok(devices.swap_remove(index)),
+        None => Err(anyhow!(
+            "No output device matches any of {:?}; available devices are {:?}",
+            specs,
+            names
+        )),
+    }
+}
+
+/// Names of the default host's output devices, in the order listed by `--listDevices`
+pub fn output_device_names() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices
+            .map(|d| d.name().unwrap_or(String::from("unknown")))
+            .collect(),
+        Err(e) => {
+            warn!("Could not list output devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HostInfo {
+    pub name: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    /// Index within the host, as listed
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub default_channels: Option<u16>,
+    pub default_sample_rate: Option<u32>,
+    pub configs: Vec<ConfigInfo>,
+}
+
+/// One range of output configurations supported by a device
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    /// Buffer size range in frames, if the platform reports one
+    pub min_buffer_size: Option<u32>,
+    pub max_buffer_size: Option<u32>,
+    pub sample_format: String,
+}
+
+/// Every available host, with its output devices and their capabilities
+pub fn list_hosts() -> Vec<HostInfo> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| cpal::host_from_id(id).ok())
+        .map(|host| {
+            let default_name = host.default_output_device().and_then(|d| d.name().ok());
+            let devices = match host.output_devices() {
+                Ok(devices) => devices
+                    .enumerate()
+                    .map(|(index, device)| describe_device(index, &device, default_name.as_deref()))
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            HostInfo {
+                name: String::from(host.id().name()),
+                devices,
+            }
+        })
+        .collect()
+}
+
+fn describe_device(index: usize, device: &rodio::Device, default_name: Option<&str>) -> DeviceInfo {
+    let name = device.name().unwrap_or(String::from("unknown"));
+    let default_config = device.default_output_config().ok();
+    let configs = match device.supported_output_configs() {
+        Ok(configs) => configs
+            .map(|c| {
+                let (min_buffer_size, max_buffer_size) = match c.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => (Some(*min), Some(*max)),
+                    SupportedBufferSize::Unknown => (None, None),
+                };
+                ConfigInfo {
+                    channels: c.channels(),
+                    min_sample_rate: c.min_sample_rate().0,
+                    max_sample_rate: c.max_sample_rate().0,
+                    min_buffer_size,
+                    max_buffer_size,
+                    sample_format: c.sample_format().to_string(),
+                }
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    DeviceInfo {
+        index,
+        is_default: default_name == Some(name.as_str()),
+        name,
+        default_channels: default_config.as_ref().map(|c| c.channels()),
+        default_sample_rate: default_config.as_ref().map(|c| c.sample_rate().0),
+        configs,
+    }
+}
+
+/// Print every host and output device (for `--listDevices`), as text or JSON
+pub fn print_devices(as_json: bool) {
+    let hosts = list_hosts();
+    if as_json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&hosts).expect("failed to serialise device list")
+        );
+        return;
+    }
+    for host in hosts {
+        println!("Host \"{}\"", host.name);
+        if host.devices.is_empty() {
+            println!("  (no output devices)");
+        }
+        for device in host.devices {
+            println!(
+                "  #{}: \"{}\"{}",
+                device.index,
+                device.name,
+                if device.is_default { " (default)" } else { "" }
+            );
+            if let (Some(channels), Some(rate)) =
+                (device.default_channels, device.default_sample_rate)
+            {
+                println!("      default: x{} channels, {} Hz", channels, rate);
+            }
+            for c in device.configs {
+                let buffer = match (c.min_buffer_size, c.max_buffer_size) {
+                    (Some(min), Some(max)) => format!("buffer {}-{} frames", min, max),
+                    _ => String::from("buffer size unknown"),
+                };
+                println!(
+                    "      x{} channels, {}-{} Hz, {}, {}",
+                    c.channels, c.min_sample_rate, c.max_sample_rate, buffer, c.sample_format
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_names() -> Vec<String> {
+    ["Built-in Output", "MOTU M4 (2)", "MOTU M4", "Focusrite USB"]
+        .map(String::from)
+        .to_vec()
+}
+
+#[cfg(test)]
+fn pick(specs: &[&str]) -> Option<usize> {
+    let matchers: Vec<DeviceMatcher> = specs.iter().map(|s| s.parse().unwrap()).collect();
+    pick_device(&matchers, &test_names(), Some(0))
+}
+
+#[test]
+fn devices_match_by_name_index_or_pattern() {
+    assert_eq!(pick(&["motu m4"]), Some(2));
+    assert_eq!(pick(&["focus"]), Some(3));
+    assert_eq!(pick(&["#1"]), Some(1));
+    assert_eq!(pick(&["3"]), Some(3));
+    assert_eq!(pick(&["/^MOTU.*\\(\\d\\)$/"]), Some(1));
+    assert_eq!(pick(&["default"]), Some(0));
+    assert_eq!(pick(&["#9"]), None);
+}
+
+#[test]
+fn devices_fall_back_in_order() {
+    assert_eq!(pick(&["RME", "focusrite", "motu"]), Some(3));
+    assert_eq!(pick(&["RME", "Apollo", "default"]), Some(0));
+    assert_eq!(pick(&["RME"]), None);
+}
+
+#[test]
+fn exact_spec_matches_only_that_name() {
+    let names = ["default", "2", "#0", "/dev/dsp", "MOTU M4 (2)", "MOTU M4"].map(String::from);
+    for (index, name) in names.iter().enumerate() {
+        let matcher: DeviceMatcher = exact_device_spec(name).parse().unwrap();
+        assert_eq!(pick_device(&[matcher], &names, Some(5)), Some(index));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{f32::consts::FRAC_PI_2, time::Duration};
+
+use rodio::{Sample, Source};
+
+/// Number of samples (across all channels) in the given duration, rounded to whole frames
+pub fn samples_in(duration: Duration, sample_rate: u32, channels: u16) -> usize {
+    let frames = (duration.as_secs_f64() * sample_rate as f64).round() as usize;
+    frames * channels as usize
+}
+
+/// Plays a loop region repeatedly, overlapping the end of each pass with the start of
+/// the next (with an equal-power crossfade), so that there is no click at the wrap point.
+///
+/// `make_pass` must return a fresh source for a single pass of the loop region each
+/// time it is called, e.g. by cloning a buffered source.
+pub struct CrossfadeLoop<S, F>
+where
+    S: Source,
+    S::Item: Sample,
+    F: FnMut() -> S,
+{
+    make_pass: F,
+    current: S,
+    next: Option<S>,
+    /// Samples per pass, before any overlap
+    pass_samples: usize,
+    crossfade_samples: usize,
+    /// Samples consumed from the current pass so far
+    position: usize,
+    /// Passes still to start, after the current one; None if looping forever
+    passes_left: Option<u32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl<S, F> CrossfadeLoop<S, F>
+where
+    S: Source,
+    S::Item: Sample,
+    F: FnMut() -> S,
+{
+    /// Play the region `count` times (or forever, if None), given the length of a single
+    /// pass and of the overlap between passes
+    pub fn new(mut make_pass: F, pass: Duration, crossfade: Duration, count: Option<u32>) -> Self {
+        let current = make_pass();
+        let (sample_rate, channels) = (current.sample_rate(), current.channels());
+        let pass_samples = samples_in(pass, sample_rate, channels);
+        CrossfadeLoop {
+            make_pass,
+            current,
+            next: None,
+            pass_samples,
+            crossfade_samples: samples_in(crossfade, sample_rate, channels).min(pass_samples / 2),
+            position: 0,
+            passes_left: count.map(|c| c.saturating_sub(1)),
+            channels,
+            sample_rate,
+        }
+    }
+
+    fn has_more_passes(&self) -> bool {
+        self.passes_left.is_none_or(|left| left > 0)
+    }
+
+    fn start_pass(&mut self) -> S {
+        if let Some(left) = &mut self.passes_left {
+            *left -= 1;
+        }
+        (self.make_pass)()
+    }
+}
+
+impl<S, F> Iterator for CrossfadeLoop<S, F>
+where
+    S: Source,
+    S::Item: Sample,
+    F: FnMut() -> S,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let overlap_start = self.pass_samples - self.crossfade_samples;
+        if self.crossfade_samples > 0
+            && self.position == overlap_start
+            && self.next.is_none()
+            && self.has_more_passes()
+        {
+            self.next = Some(self.start_pass());
+        }
+
+        let Some(next) = &mut self.next else {
+            // No overlap in progress, e.g. during the final pass, or with no crossfade
+            let pass_over = self.crossfade_samples == 0 && self.position >= self.pass_samples;
+            if !pass_over {
+                if let Some(sample) = self.current.next() {
+                    self.position += 1;
+                    return Some(sample);
+                }
+            }
+            // The pass has ended (perhaps earlier than expected); carry straight on
+            // with the next one, if any
+            if !self.has_more_passes() {
+                return None;
+            }
+            self.current = self.start_pass();
+            self.position = 1;
+            return self.current.next();
+        };
+
+        let into_fade = (self.position - overlap_start) as f32 / self.crossfade_samples as f32;
+        let outgoing = self.current.next().unwrap_or(S::Item::zero_value());
+        let incoming = next.next().unwrap_or(S::Item::zero_value());
+        let mixed = outgoing
+            .amplify((into_fade * FRAC_PI_2).cos())
+            .saturating_add(incoming.amplify((into_fade * FRAC_PI_2).sin()));
+
+        self.position += 1;
+        if self.position >= self.pass_samples {
+            // The incoming pass is now the current one, already some way in
+            self.current = self.next.take().unwrap();
+            self.position = self.crossfade_samples;
+        }
+        Some(mixed)
+    }
+}
+
+impl<S, F> Source for CrossfadeLoop<S, F>
+where
+    S: Source,
+    S::Item: Sample,
+    F: FnMut() -> S,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+fn constant_pass(value: f32) -> impl FnMut() -> rodio::buffer::SamplesBuffer<f32> {
+    move || rodio::buffer::SamplesBuffer::new(1, 1000, vec![value; 100])
+}
+
+#[test]
+fn finite_passes_overlap() {
+    let looped = CrossfadeLoop::new(
+        constant_pass(0.5),
+        Duration::from_millis(100),
+        Duration::from_millis(20),
+        Some(3),
+    );
+    let samples: Vec<f32> = looped.collect();
+    // Three passes of 100 samples, with two overlaps of 20
+    assert_eq!(samples.len(), 3 * 100 - 2 * 20);
+    // Equal-power crossfade of identical material never drops below the original level
+    assert!(samples.iter().all(|s| *s >= 0.5 - 1e-4));
+}
+
+#[test]
+fn infinite_loop_keeps_going() {
+    let looped = CrossfadeLoop::new(
+        constant_pass(0.25),
+        Duration::from_millis(100),
+        Duration::from_millis(10),
+        None,
+    );
+    assert_eq!(looped.take(1000).count(), 1000);
+}
+
+#[test]
+fn short_pass_moves_on_to_the_next() {
+    // Passes of 60 samples, where 100 were expected
+    let looped = CrossfadeLoop::new(
+        || rodio::buffer::SamplesBuffer::new(1, 1000, vec![0.5f32; 60]),
+        Duration::from_millis(100),
+        Duration::from_millis(20),
+        Some(3),
+    );
+    assert_eq!(looped.count(), 3 * 60);
+}
+
+#[test]
+fn no_crossfade_joins_passes_end_to_end() {
+    let looped = CrossfadeLoop::new(
+        constant_pass(0.5),
+        Duration::from_millis(100),
+        Duration::ZERO,
+        Some(3),
+    );
+    let samples: Vec<f32> = looped.collect();
+    assert_eq!(samples.len(), 3 * 100);
+    assert!(samples.iter().all(|s| *s == 0.5));
+}
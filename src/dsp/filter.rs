@@ -0,0 +1,328 @@
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use rodio::{cpal::FromSample, Sample, Source};
+use serde::{Deserialize, Serialize};
+
+/// Resonance (Q) giving a flat response at the cutoff
+pub const DEFAULT_RESONANCE: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Coefficients are only recalculated (if the controls have changed) this often
+const CONTROL_INTERVAL_SAMPLES: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterKind {
+    /// Pass the signal through unchanged
+    Bypass,
+    LowPass,
+    HighPass,
+    BandPass,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterSettings {
+    #[serde(rename = "type")]
+    pub kind: FilterKind,
+    /// Cutoff (or centre, for band-pass) frequency in Hz
+    pub cutoff: f32,
+    /// Q; higher values give a sharper peak at the cutoff
+    pub resonance: Option<f32>,
+}
+
+impl FilterSettings {
+    /// Check that the cutoff and resonance are usable
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_positive("cutoff", Some(self.cutoff))?;
+        check_positive("resonance", self.resonance)
+    }
+}
+
+fn check_positive(name: &str, value: Option<f32>) -> anyhow::Result<()> {
+    match value {
+        Some(value) if !value.is_finite() || value <= 0. => Err(anyhow!(
+            "Filter {name} must be a positive number, not {value}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Changes to some or all of the filter parameters, e.g. from a clipCommands message
+#[derive(Debug, Default, Clone)]
+pub struct FilterUpdate {
+    pub kind: Option<FilterKind>,
+    pub cutoff: Option<f32>,
+    pub resonance: Option<f32>,
+}
+
+impl FilterUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.kind.is_none() && self.cutoff.is_none() && self.resonance.is_none()
+    }
+
+    /// Check that any cutoff and resonance given are usable
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_positive("cutoff", self.cutoff)?;
+        check_positive("resonance", self.resonance)
+    }
+}
+
+/// Filter parameters shared between the playback controls and the audio thread
+pub struct FilterControl {
+    kind: AtomicU8,
+    cutoff: AtomicU32,
+    resonance: AtomicU32,
+}
+
+impl FilterControl {
+    pub fn new(settings: Option<&FilterSettings>) -> Arc<Self> {
+        let control = FilterControl {
+            kind: AtomicU8::new(FilterKind::Bypass as u8),
+            cutoff: AtomicU32::new(1000f32.to_bits()),
+            resonance: AtomicU32::new(DEFAULT_RESONANCE.to_bits()),
+        };
+        if let Some(settings) = settings {
+            control.set_kind(settings.kind);
+            control.set_cutoff(settings.cutoff);
+            control.set_resonance(settings.resonance.unwrap_or(DEFAULT_RESONANCE));
+        }
+        Arc::new(control)
+    }
+
+    pub fn kind(&self) -> FilterKind {
+        match self.kind.load(Ordering::Relaxed) {
+            1 => FilterKind::LowPass,
+            2 => FilterKind::HighPass,
+            3 => FilterKind::BandPass,
+            _ => FilterKind::Bypass,
+        }
+    }
+
+    pub fn cutoff(&self) -> f32 {
+        f32::from_bits(self.cutoff.load(Ordering::Relaxed))
+    }
+
+    pub fn resonance(&self) -> f32 {
+        f32::from_bits(self.resonance.load(Ordering::Relaxed))
+    }
+
+    pub fn set_kind(&self, kind: FilterKind) {
+        self.kind.store(kind as u8, Ordering::Relaxed);
+    }
+
+    pub fn set_cutoff(&self, cutoff: f32) {
+        self.cutoff.store(cutoff.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_resonance(&self, resonance: f32) {
+        self.resonance.store(resonance.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Normalised biquad coefficients (i.e. a0 = 1), as per the RBJ Audio EQ Cookbook
+#[derive(Clone, Copy, PartialEq)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    fn new(kind: FilterKind, cutoff: f32, resonance: f32, sample_rate: u32) -> Option<Self> {
+        // NaN would pass straight through the clamp, and silence the output for good
+        if !cutoff.is_finite() || !resonance.is_finite() {
+            return None;
+        }
+        let cutoff = cutoff.clamp(10., sample_rate as f32 * 0.45);
+        let w0 = 2. * PI * cutoff / sample_rate as f32;
+        let alpha = w0.sin() / (2. * resonance.max(0.1));
+        let cos = w0.cos();
+        let (b0, b1, b2) = match kind {
+            FilterKind::Bypass => return None,
+            FilterKind::LowPass => ((1. - cos) / 2., 1. - cos, (1. - cos) / 2.),
+            FilterKind::HighPass => ((1. + cos) / 2., -(1. + cos), (1. + cos) / 2.),
+            FilterKind::BandPass => (alpha, 0., -alpha),
+        };
+        let a0 = 1. + alpha;
+        Some(Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: -2. * cos / a0,
+            a2: (1. - alpha) / a0,
+        })
+    }
+}
+
+/// Previous inputs and outputs, for one channel
+#[derive(Clone, Copy, Default)]
+struct History {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+/// Biquad low-pass, high-pass or band-pass filter, controlled (live) via a shared
+/// [FilterControl]
+pub struct Filter<S> {
+    input: S,
+    control: Arc<FilterControl>,
+    /// Control values the current coefficients were calculated from
+    applied: (FilterKind, f32, f32),
+    coefficients: Option<Coefficients>,
+    history: Vec<History>,
+    channel: usize,
+    until_control_check: usize,
+}
+
+impl<S> Filter<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    pub fn new(input: S, control: Arc<FilterControl>) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let mut filter = Filter {
+            input,
+            control,
+            applied: (FilterKind::Bypass, 0., 0.),
+            coefficients: None,
+            history: vec![History::default(); channels],
+            channel: 0,
+            until_control_check: 0,
+        };
+        filter.check_controls();
+        filter
+    }
+
+    fn check_controls(&mut self) {
+        let current = (
+            self.control.kind(),
+            self.control.cutoff(),
+            self.control.resonance(),
+        );
+        if current != self.applied {
+            let (kind, cutoff, resonance) = current;
+            if self.applied.0 != kind {
+                // Avoid a burst of noise from history built up by another filter type
+                self.history.fill(History::default());
+            }
+            self.coefficients =
+                Coefficients::new(kind, cutoff, resonance, self.input.sample_rate());
+            self.applied = current;
+        }
+    }
+}
+
+impl<S> Iterator for Filter<S>
+where
+    S: Source,
+    S::Item: Sample + FromSample<f32>,
+    f32: FromSample<S::Item>,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.until_control_check == 0 {
+            self.check_controls();
+            self.until_control_check = CONTROL_INTERVAL_SAMPLES * self.history.len();
+        }
+        self.until_control_check -= 1;
+
+        let sample = self.input.next()?;
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.history.len();
+
+        let Some(c) = self.coefficients else {
+            return Some(sample);
+        };
+        let h = &mut self.history[channel];
+        let x = f32::from_sample_(sample);
+        let y = c.b0 * x + c.b1 * h.x1 + c.b2 * h.x2 - c.a1 * h.y1 - c.a2 * h.y2;
+        *h = History {
+            x1: x,
+            x2: h.x1,
+            y1: y,
+            y2: h.y1,
+        };
+        Some(S::Item::from_sample_(y))
+    }
+}
+
+impl<S> Source for Filter<S>
+where
+    S: Source,
+    S::Item: Sample + FromSample<f32>,
+    f32: FromSample<S::Item>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+fn filtered_level(kind: FilterKind, cutoff: f32, frequency: f32) -> f32 {
+    let sample_rate = 44100;
+    let sine: Vec<f32> = (0..sample_rate)
+        .map(|i| (2. * PI * frequency * i as f32 / sample_rate as f32).sin() * 0.5)
+        .collect();
+    let input = rodio::buffer::SamplesBuffer::new(1, sample_rate, sine);
+    let control = FilterControl::new(Some(&FilterSettings {
+        kind,
+        cutoff,
+        resonance: None,
+    }));
+    // Skip the first part, while the filter settles
+    let output: Vec<f32> = Filter::new(input, control).skip(4410).collect();
+    output.iter().map(|s| s.abs()).fold(0., f32::max)
+}
+
+#[test]
+fn low_pass_removes_high_frequencies() {
+    assert!(filtered_level(FilterKind::LowPass, 500., 100.) > 0.45);
+    assert!(filtered_level(FilterKind::LowPass, 500., 8000.) < 0.01);
+}
+
+#[test]
+fn high_pass_removes_low_frequencies() {
+    assert!(filtered_level(FilterKind::HighPass, 2000., 50.) < 0.01);
+    assert!(filtered_level(FilterKind::HighPass, 2000., 10000.) > 0.45);
+}
+
+#[test]
+fn unusable_values_are_rejected() {
+    let update = FilterUpdate {
+        cutoff: Some(f32::NAN),
+        ..Default::default()
+    };
+    assert!(update.validate().is_err());
+    let update = FilterUpdate {
+        resonance: Some(0.),
+        ..Default::default()
+    };
+    assert!(update.validate().is_err());
+    assert!(Coefficients::new(FilterKind::LowPass, f32::INFINITY, 1., 48000).is_none());
+}
@@ -0,0 +1,24 @@
+pub mod crossfade;
+pub mod filter;
+pub mod reverb;
+pub mod skip;
+pub mod speed;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A value shared between the playback controls and the audio thread
+pub struct SharedF32(AtomicU32);
+
+impl SharedF32 {
+    pub fn new(value: f32) -> Self {
+        SharedF32(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
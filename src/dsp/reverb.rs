@@ -0,0 +1,190 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+/// Freeverb tunings, in samples at 44.1kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const FIXED_GAIN: f32 = 0.015;
+
+/// Reverb settings, each in the range 0-1; any not given are left unchanged
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverbUpdate {
+    pub size: Option<f32>,
+    pub damping: Option<f32>,
+    pub wet: Option<f32>,
+}
+
+impl ReverbUpdate {
+    /// Take on any settings given in the other update
+    pub fn combine(&mut self, other: &ReverbUpdate) {
+        self.size = other.size.or(self.size);
+        self.damping = other.damping.or(self.damping);
+        self.wet = other.wet.or(self.wet);
+    }
+
+    /// Check that any settings given are within 0-1
+    pub fn validate(&self) -> anyhow::Result<()> {
+        check_level("Reverb size", self.size)?;
+        check_level("Reverb damping", self.damping)?;
+        check_level("Reverb wet", self.wet)
+    }
+}
+
+/// Check a setting that must be from 0 to 1, such as a reverb send level
+pub fn check_level(name: &str, value: Option<f32>) -> anyhow::Result<()> {
+    match value {
+        // Also rejects NaN, which fails every comparison
+        Some(value) if !(0. ..=1.).contains(&value) => {
+            Err(anyhow!("{name} must be a number from 0 to 1, not {value}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Comb {
+            buffer: vec![0.; length.max(1)],
+            index: 0,
+            filter_store: 0.,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = output * (1. - damping) + self.filter_store * damping;
+        self.buffer[self.index] = input + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct AllPass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl AllPass {
+    fn new(length: usize) -> Self {
+        AllPass {
+            buffer: vec![0.; length.max(1)],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// One channel of a Freeverb-style reverb: parallel comb filters, then all-pass
+/// filters in series
+struct FreeverbChannel {
+    combs: Vec<Comb>,
+    all_passes: Vec<AllPass>,
+}
+
+impl FreeverbChannel {
+    fn new(sample_rate: u32, spread: usize) -> Self {
+        let scale = |samples: usize| (samples + spread) * sample_rate as usize / 44100;
+        FreeverbChannel {
+            combs: COMB_TUNINGS.iter().map(|t| Comb::new(scale(*t))).collect(),
+            all_passes: ALLPASS_TUNINGS
+                .iter()
+                .map(|t| AllPass::new(scale(*t)))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let combined: f32 = self
+            .combs
+            .iter_mut()
+            .map(|c| c.process(input, feedback, damping))
+            .sum();
+        self.all_passes
+            .iter_mut()
+            .fold(combined, |signal, a| a.process(signal))
+    }
+}
+
+/// Freeverb-style stereo reverb, fed with a mono sum of everything sent to it
+pub struct Reverb {
+    left: FreeverbChannel,
+    right: FreeverbChannel,
+    size: f32,
+    damping: f32,
+    wet: f32,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32) -> Self {
+        Reverb {
+            left: FreeverbChannel::new(sample_rate, 0),
+            right: FreeverbChannel::new(sample_rate, STEREO_SPREAD),
+            size: 0.5,
+            damping: 0.5,
+            wet: 0.3,
+        }
+    }
+
+    pub fn update(&mut self, update: &ReverbUpdate) {
+        if let Some(size) = update.size {
+            self.size = size.clamp(0., 1.);
+        }
+        if let Some(damping) = update.damping {
+            self.damping = damping.clamp(0., 1.);
+        }
+        if let Some(wet) = update.wet {
+            self.wet = wet.clamp(0., 1.);
+        }
+    }
+
+    /// Process one frame of input, returning left and right outputs
+    pub fn process(&mut self, input: f32) -> [f32; 2] {
+        let input = input * FIXED_GAIN;
+        let (feedback, damping) = (self.size * 0.28 + 0.7, self.damping * 0.4);
+        [
+            self.left.process(input, feedback, damping) * self.wet,
+            self.right.process(input, feedback, damping) * self.wet,
+        ]
+    }
+}
+
+#[test]
+fn impulse_leaves_a_tail() {
+    let mut reverb = Reverb::new(44100);
+    let mut output = vec![reverb.process(1.)];
+    output.extend((0..44100).map(|_| reverb.process(0.)));
+    let audible = output
+        .iter()
+        .filter(|[l, r]| l.abs() > 1e-4 || r.abs() > 1e-4);
+    // Still ringing after half a second
+    assert!(output[22050..].iter().any(|[l, _]| l.abs() > 1e-5));
+    assert!(audible.count() > 1000);
+}
+
+#[test]
+fn unusable_settings_are_rejected() {
+    let update = |size| ReverbUpdate {
+        size,
+        ..Default::default()
+    };
+    assert!(update(Some(0.8)).validate().is_ok());
+    assert!(update(None).validate().is_ok());
+    assert!(update(Some(f32::NAN)).validate().is_err());
+    assert!(update(Some(1.5)).validate().is_err());
+    assert!(check_level("reverbSend", Some(f32::INFINITY)).is_err());
+    assert!(check_level("reverbSend", Some(-0.1)).is_err());
+}
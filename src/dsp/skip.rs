@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use rodio::{Sample, Source};
+
+use super::crossfade::samples_in;
+
+/// Skips the start of the source straight away, in whole frames, like rodio's
+/// `skip_duration` but without adding another layer to the source. This decodes
+/// everything skipped, so is done while building a voice, never on the audio thread.
+pub fn skip_now<S>(source: &mut S, duration: Duration)
+where
+    S: Source,
+    S::Item: Sample,
+{
+    if duration.is_zero() || source.next().is_none() {
+        return;
+    }
+    // The format is only certain once the source is playing, e.g. for a queue
+    let channels = source.channels() as usize;
+    let samples = samples_in(duration, source.sample_rate(), source.channels()).max(channels);
+    if samples > 1 {
+        source.nth(samples - 2);
+    }
+}
+
+#[test]
+fn skips_whole_frames_straight_away() {
+    let input = || {
+        rodio::buffer::SamplesBuffer::new(2, 1000, (0..100).map(|i| i as f32).collect::<Vec<_>>())
+    };
+    let mut skipped = input();
+    skip_now(&mut skipped, Duration::from_millis(10));
+    assert_eq!(skipped.next(), Some(20.));
+    // Less than a frame still skips a whole one
+    let mut skipped = input();
+    skip_now(&mut skipped, Duration::from_micros(100));
+    assert_eq!(skipped.next(), Some(2.));
+}
@@ -0,0 +1,124 @@
+use std::{sync::Arc, time::Duration};
+
+use rodio::{cpal::FromSample, Sample, Source};
+
+use super::SharedF32;
+
+/// Plays the input faster or slower (and therefore higher or lower), by linear
+/// interpolation between frames. Unlike rodio's own `Speed`, the sample rate reported
+/// stays the same, so the rate can be changed at any time.
+pub struct VariableSpeed<S> {
+    input: S,
+    rate: Arc<SharedF32>,
+    channels: usize,
+    current: Vec<f32>,
+    next: Vec<f32>,
+    /// Position between the current and next input frames, from 0 to 1
+    position: f32,
+    output: Vec<f32>,
+    /// Index of the next sample to return from `output`
+    output_index: usize,
+    /// The first frames are only read once playback starts, not on creation
+    started: bool,
+}
+
+impl<S> VariableSpeed<S>
+where
+    S: Source,
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    pub fn new(input: S, rate: Arc<SharedF32>) -> Self {
+        let channels = input.channels().max(1) as usize;
+        VariableSpeed {
+            input,
+            rate,
+            channels,
+            current: Vec::with_capacity(channels),
+            next: Vec::with_capacity(channels),
+            position: 0.,
+            output: vec![0.; channels],
+            output_index: channels,
+            started: false,
+        }
+    }
+
+    fn read_frame(&mut self) -> Option<Vec<f32>> {
+        let frame: Vec<f32> = (&mut self.input)
+            .take(self.channels)
+            .map(f32::from_sample_)
+            .collect();
+        (frame.len() == self.channels).then_some(frame)
+    }
+}
+
+impl<S> Iterator for VariableSpeed<S>
+where
+    S: Source,
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if !self.started {
+            self.started = true;
+            self.current = self.read_frame().unwrap_or_default();
+            self.next = self.read_frame().unwrap_or_default();
+        }
+        if self.output_index == self.channels {
+            if self.current.is_empty() {
+                return None;
+            }
+            for (i, sample) in self.output.iter_mut().enumerate() {
+                // The last frame has nothing after it to interpolate towards
+                let next = self.next.get(i).copied().unwrap_or(self.current[i]);
+                *sample = self.current[i] + (next - self.current[i]) * self.position;
+            }
+            self.output_index = 0;
+
+            self.position += self.rate.get().max(0.);
+            while self.position >= 1. {
+                self.position -= 1.;
+                self.current = std::mem::take(&mut self.next);
+                if self.current.is_empty() {
+                    break;
+                }
+                self.next = self.read_frame().unwrap_or_default();
+            }
+        }
+        let sample = self.output[self.output_index];
+        self.output_index += 1;
+        Some(sample)
+    }
+}
+
+impl<S> Source for VariableSpeed<S>
+where
+    S: Source,
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[test]
+fn double_speed_halves_length() {
+    let input = rodio::buffer::SamplesBuffer::new(2, 1000, vec![0.5f32; 2000]);
+    let fast = VariableSpeed::new(input, Arc::new(SharedF32::new(2.0)));
+    assert_eq!(fast.count(), 1000);
+}
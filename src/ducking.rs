@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::db_to_gain;
+
+const DEFAULT_ATTACK_MS: u64 = 300;
+const DEFAULT_RELEASE_MS: u64 = 1000;
+
+/// A "sidechain" style rule, as specified in the Sample Bank JSON: while any clip
+/// tagged with `trigger` is playing, all clips tagged with `target` are reduced
+/// by `reduction_db` decibels.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DuckingRule {
+    pub trigger: String,
+    pub target: String,
+    /// Positive value in dB, e.g. `12.0` to reduce the target clips by 12 dB
+    pub reduction_db: f32,
+    /// Time in milliseconds to reach full reduction
+    pub attack_duration: Option<u64>,
+    /// Time in milliseconds to return to normal level, once no trigger clips are playing
+    pub release_duration: Option<u64>,
+}
+
+/// Keeps track of the current reduction applied by a single DuckingRule, moving
+/// linearly (in dB) towards full reduction or none, depending on whether the rule
+/// is currently triggered.
+pub struct Ducker {
+    rule: DuckingRule,
+    current_db: f32,
+}
+
+impl Ducker {
+    pub fn new(rule: DuckingRule) -> Self {
+        Ducker {
+            rule,
+            current_db: 0.,
+        }
+    }
+
+    pub fn rule(&self) -> &DuckingRule {
+        &self.rule
+    }
+
+    pub fn update(&mut self, triggered: bool, elapsed: Duration) {
+        let full = self.rule.reduction_db.abs();
+        if full == 0. {
+            return;
+        }
+        let (target, ramp_ms) = if triggered {
+            (full, self.rule.attack_duration.unwrap_or(DEFAULT_ATTACK_MS))
+        } else {
+            (0., self.rule.release_duration.unwrap_or(DEFAULT_RELEASE_MS))
+        };
+        if ramp_ms == 0 {
+            self.current_db = target;
+            return;
+        }
+        let step = full * elapsed.as_millis() as f32 / ramp_ms as f32;
+        self.current_db = if target > self.current_db {
+            f32::min(self.current_db + step, target)
+        } else {
+            f32::max(self.current_db - step, target)
+        };
+    }
+
+    /// Current reduction, expressed as a volume multiplier
+    pub fn gain(&self) -> f32 {
+        db_to_gain(-self.current_db)
+    }
+}
+
+/// Volume multiplier for a clip with the given tags, from every rule targeting it
+pub fn combined_gain(duckers: &[Ducker], tags: &[String]) -> f32 {
+    duckers
+        .iter()
+        .filter(|ducker| {
+            tags.iter()
+                .any(|tag| tag.eq_ignore_ascii_case(&ducker.rule.target))
+        })
+        .fold(1.0, |acc, ducker| acc * ducker.gain())
+}
+
+#[cfg(test)]
+fn test_rule() -> DuckingRule {
+    DuckingRule {
+        trigger: "voice".into(),
+        target: "ambience".into(),
+        reduction_db: 12.,
+        attack_duration: Some(300),
+        release_duration: Some(1000),
+    }
+}
+
+#[test]
+fn untriggered_is_unity_gain() {
+    let mut ducker = Ducker::new(test_rule());
+    ducker.update(false, Duration::from_millis(100));
+    assert_eq!(ducker.gain(), 1.0);
+}
+
+#[test]
+fn attack_reaches_full_reduction() {
+    let mut ducker = Ducker::new(test_rule());
+    ducker.update(true, Duration::from_millis(150));
+    assert_eq!(ducker.current_db, 6.);
+    ducker.update(true, Duration::from_millis(500));
+    assert_eq!(ducker.current_db, 12.);
+    assert!((ducker.gain() - 0.25118864).abs() < 0.0001);
+}
+
+#[test]
+fn release_returns_to_unity() {
+    let mut ducker = Ducker::new(test_rule());
+    ducker.update(true, Duration::from_millis(300));
+    ducker.update(false, Duration::from_millis(500));
+    assert_eq!(ducker.current_db, 6.);
+    ducker.update(false, Duration::from_millis(500));
+    assert_eq!(ducker.gain(), 1.0);
+}
+
+#[test]
+fn only_targeted_tags_are_ducked() {
+    let mut ducker = Ducker::new(test_rule());
+    ducker.update(true, Duration::from_millis(300));
+    let duckers = [ducker];
+    assert_eq!(combined_gain(&duckers, &["music".into()]), 1.0);
+    let gain = combined_gain(&duckers, &["music".into(), "Ambience".into()]);
+    assert!((gain - 0.25118864).abs() < 0.0001);
+}
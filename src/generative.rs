@@ -0,0 +1,164 @@
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+
+use crate::{
+    clock,
+    model::ActionQueueItem,
+    picker::{ClipPicker, RandomPickOptions},
+    tempo::Quantize,
+    variants::random_in_range,
+};
+
+const DEFAULT_INTERVAL_MS: [u64; 2] = [2000, 8000];
+
+/// Options for a "randomInterval" scene, all of which are optional in the Scene Message
+#[derive(Debug, Default, Clone)]
+pub struct GenerativeSettings {
+    /// [min, max] time in milliseconds between triggers
+    pub interval_range: Option<[u64; 2]>,
+    pub volume_range: Option<[f32; 2]>,
+    /// [min, max] pan position; if omitted, clips will use their own (or default) panning
+    pub pan_range: Option<[f32; 2]>,
+    pub pan_spread: Option<f32>,
+    /// Maximum number of clips from this layer allowed to play at the same time
+    pub max_overlap: Option<usize>,
+    pub pick: RandomPickOptions,
+}
+
+/// A layer that keeps triggering clips (once, never looping) from a pool at random
+/// intervals, until it is replaced or cleared by another scene.
+pub struct GenerativeLayer {
+    clip_names: Vec<String>,
+    settings: GenerativeSettings,
+    fade: Option<Duration>,
+    quantize: Option<Quantize>,
+    next_trigger: SystemTime,
+}
+
+impl GenerativeLayer {
+    /// The first clip is triggered immediately
+    pub fn new(
+        clip_names: Vec<String>,
+        settings: GenerativeSettings,
+        fade: Option<Duration>,
+        quantize: Option<Quantize>,
+    ) -> Self {
+        GenerativeLayer {
+            clip_names,
+            settings,
+            fade,
+            quantize,
+            next_trigger: clock::now(),
+        }
+    }
+
+    pub fn clip_names(&self) -> &[String] {
+        &self.clip_names
+    }
+
+    pub fn pick_options(&self) -> &RandomPickOptions {
+        &self.settings.pick
+    }
+
+    pub fn interval_range(&self) -> [u64; 2] {
+        self.settings.interval_range.unwrap_or(DEFAULT_INTERVAL_MS)
+    }
+
+    /// Returns a Play action if it is time for the next trigger, given the number
+    /// of clips from the pool that are currently playing and the weights to use
+    pub fn poll(
+        &mut self,
+        currently_playing: usize,
+        weights: &[f32],
+        picker: &mut ClipPicker,
+    ) -> Option<anyhow::Result<ActionQueueItem>> {
+        let now = clock::now();
+        if now < self.next_trigger {
+            return None;
+        }
+
+        let [min, max] = self.interval_range();
+        let rng = picker.rng();
+        let interval = if max > min {
+            rng.gen_range(min..max)
+        } else {
+            min
+        };
+        self.next_trigger = now + Duration::from_millis(interval);
+
+        if let Some(max_overlap) = self.settings.max_overlap {
+            if currently_playing >= max_overlap {
+                return None;
+            }
+        }
+
+        let panning = random_in_range(self.settings.pan_range, rng)
+            .map(|position| (position, self.settings.pan_spread.unwrap_or(0.)));
+        let volume = random_in_range(self.settings.volume_range, rng);
+
+        let avoid_repeat = self.settings.pick.avoid_repeat.unwrap_or_default();
+        Some(
+            picker
+                .pick(&self.clip_names, weights, avoid_repeat)
+                .map(|name| {
+                    ActionQueueItem::Play(
+                        name,
+                        volume,
+                        self.fade,
+                        false,
+                        panning,
+                        self.quantize,
+                        Default::default(),
+                    )
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+fn layer(settings: GenerativeSettings) -> GenerativeLayer {
+    GenerativeLayer::new(vec!["a".into(), "b".into()], settings, None, None)
+}
+
+#[test]
+fn intervals_stay_within_range() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    clock::set_virtual(start);
+    let mut picker = ClipPicker::new(Some(5));
+    let mut layer = layer(GenerativeSettings {
+        interval_range: Some([500, 1500]),
+        ..Default::default()
+    });
+    let mut now = start;
+    for _ in 0..50 {
+        assert!(layer.poll(0, &[1., 1.], &mut picker).is_some());
+        let interval = layer.next_trigger.duration_since(now).unwrap();
+        assert!(interval >= Duration::from_millis(500));
+        assert!(interval < Duration::from_millis(1500));
+
+        clock::set_virtual(layer.next_trigger - Duration::from_millis(1));
+        assert!(layer.poll(0, &[1., 1.], &mut picker).is_none());
+        now = layer.next_trigger;
+        clock::set_virtual(now);
+    }
+    clock::use_system();
+}
+
+#[test]
+fn max_overlap_skips_triggers() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    clock::set_virtual(start);
+    let mut picker = ClipPicker::new(Some(9));
+    let mut layer = layer(GenerativeSettings {
+        interval_range: Some([1000, 1000]),
+        max_overlap: Some(2),
+        ..Default::default()
+    });
+    assert!(layer.poll(2, &[1., 1.], &mut picker).is_none());
+    // The skipped trigger still waits a full interval before trying again
+    assert_eq!(layer.next_trigger, start + Duration::from_secs(1));
+    clock::set_virtual(layer.next_trigger);
+    assert!(layer.poll(1, &[1., 1.], &mut picker).unwrap().is_ok());
+    clock::use_system();
+}
@@ -1,22 +1,50 @@
 use std::path::{Path, PathBuf};
 
 use log::*;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::{playback::PanWithRange, utils::parse_optional_panning};
+use crate::{
+    dsp::{filter::FilterSettings, reverb::check_level},
+    ducking::DuckingRule,
+    playback::PanWithRange,
+    remote_control::receive::SceneMessage,
+    tempo::TempoSettings,
+    timetable::{QuietHours, ScheduleEntry},
+    utils::{parse_optional_panning, resolve_rate},
+    variants::{VariantSelection, VariantSelector},
+};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SoundBank {
     clips: Vec<AudioClipOnDisk>,
-    // scenes: Vec<Scene>,
+    #[serde(default)]
+    ducking: Vec<DuckingRule>,
+    tempo: Option<TempoSettings>,
+    #[serde(default)]
+    scenes: Vec<Scene>,
+    #[serde(default)]
+    schedule: Vec<ScheduleEntry>,
+    #[serde(default)]
+    quiet_hours: Vec<QuietHours>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AudioClipOnDisk {
     name: String,
-    path: String,
+    path: Option<String>,
+    /// Alternative files for the same clip, one of which is picked (together with
+    /// `path`, if any) each time the clip is triggered
+    #[serde(default)]
+    variants: Vec<String>,
+    #[serde(default)]
+    selection: VariantSelection,
+    /// Optional [min, max] random volume multiplier, applied on every trigger
+    volume_range: Option<[f32; 2]>,
+    /// Optional [min, max] random pitch shift, in semitones, applied on every trigger
+    pitch_range: Option<[f32; 2]>,
     // #[serde(default)]
     // frames_count: u32,
     // #[serde(default)]
@@ -24,13 +52,41 @@ pub struct AudioClipOnDisk {
     volume: Option<f32>,
     pan_position: Option<f32>,
     pan_spread: Option<f32>,
+    /// Relative likelihood of being picked in random scenes, if not specified by the message
+    weight: Option<f32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Milliseconds from the start of the file to begin playback
+    start_offset: Option<u64>,
+    /// Loop region in milliseconds; defaults to the whole file (after any start offset)
+    loop_start: Option<u64>,
+    loop_end: Option<u64>,
+    /// Play the loop region this many times, then continue to the end of the file
+    loop_count: Option<u32>,
+    /// Milliseconds of overlap between the end of each pass of the loop and the start of
+    /// the next, for recordings without clean loop points
+    loop_crossfade: Option<u64>,
+    /// Playback rate multiplier; alternatively, `pitch` in semitones
+    rate: Option<f32>,
+    pitch: Option<f32>,
+    filter: Option<FilterSettings>,
+    /// Level sent to the shared reverb, from 0 (dry only, the default) to 1
+    reverb_send: Option<f32>,
+    /// Decode into memory when the bank loads, so that triggers start instantly
+    #[serde(default)]
+    preload: bool,
+    #[serde(skip)]
+    selector: VariantSelector,
 }
 
-// #[derive(Serialize, Deserialize)]
-// #[serde(rename_all = "camelCase")]
-// pub struct Scene {
-//     pub clips: Vec<String>,
-// }
+/// A named scene, with the same fields as a Scene Message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Scene {
+    pub name: String,
+    #[serde(flatten)]
+    pub message: SceneMessage,
+}
 
 impl AudioClipOnDisk {
     pub fn name(&self) -> &str {
@@ -42,8 +98,24 @@ impl AudioClipOnDisk {
     // pub fn sample_rate(&self) -> u32 {
     //     self.sample_rate
     // }
-    pub fn path(&self) -> &str {
-        &self.path
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.path.iter().chain(self.variants.iter())
+    }
+    /// Choose the file to use for the next playback, according to the selection mode
+    pub fn pick_variant(&mut self, rng: &mut impl Rng) -> &str {
+        let index = self
+            .selector
+            .pick(self.selection, self.paths().count(), rng);
+        self.paths()
+            .nth(index)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+    pub fn volume_range(&self) -> Option<[f32; 2]> {
+        self.volume_range
+    }
+    pub fn pitch_range(&self) -> Option<[f32; 2]> {
+        self.pitch_range
     }
     pub fn volume(&self) -> Option<f32> {
         self.volume
@@ -51,6 +123,40 @@ impl AudioClipOnDisk {
     pub fn panning(&self) -> Option<PanWithRange> {
         parse_optional_panning(self.pan_position, self.pan_spread)
     }
+    pub fn weight(&self) -> f32 {
+        self.weight.unwrap_or(1.0)
+    }
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn start_offset(&self) -> Option<u64> {
+        self.start_offset
+    }
+    pub fn loop_start(&self) -> Option<u64> {
+        self.loop_start
+    }
+    pub fn loop_end(&self) -> Option<u64> {
+        self.loop_end
+    }
+    pub fn loop_count(&self) -> Option<u32> {
+        self.loop_count
+    }
+    pub fn loop_crossfade(&self) -> Option<u64> {
+        self.loop_crossfade
+    }
+    pub fn filter(&self) -> Option<&FilterSettings> {
+        self.filter.as_ref()
+    }
+    pub fn reverb_send(&self) -> Option<f32> {
+        self.reverb_send
+    }
+    pub fn preload(&self) -> bool {
+        self.preload
+    }
+    /// Already checked when loading, so either rate or pitch (or neither) is set
+    pub fn rate(&self) -> Option<f32> {
+        resolve_rate(self.rate, self.pitch).unwrap_or_default()
+    }
 }
 
 pub fn get_sound_asset_path(assets_path: PathBuf, base_path: &str) -> String {
@@ -68,29 +174,80 @@ impl SoundBank {
                         .clips
                         .iter()
                         .map(|sample| {
-                            let path_str = get_sound_asset_path(
-                                json_path.parent().unwrap().to_path_buf(),
-                                &sample.path,
-                            );
-                            let path = Path::new(&path_str);
+                            let base_path = json_path.parent().unwrap().to_path_buf();
+                            let resolve = |p: &String| -> String {
+                                get_sound_asset_path(base_path.clone(), p)
+                            };
+                            if sample.path.is_none() && sample.variants.is_empty() {
+                                panic!("Clip \"{}\" has no path or variants", sample.name);
+                            }
+                            if let Err(e) = resolve_rate(sample.rate, sample.pitch) {
+                                panic!("Clip \"{}\": {}", sample.name, e);
+                            }
+                            for pitch in sample.pitch_range.into_iter().flatten() {
+                                if let Err(e) = resolve_rate(None, Some(pitch)) {
+                                    panic!("Clip \"{}\" pitchRange: {}", sample.name, e);
+                                }
+                            }
+                            if let Some(Err(e)) = sample.filter.as_ref().map(|f| f.validate()) {
+                                panic!("Clip \"{}\": {}", sample.name, e);
+                            }
+                            if let Err(e) = check_level("reverbSend", sample.reverb_send) {
+                                panic!("Clip \"{}\": {}", sample.name, e);
+                            }
                             // let (frames_count, sample_rate) = read_length_and_rate(path, mono_only);
                             let volume = sample.volume;
                             // let panning =
                             //     parse_optional_panning(sample.pan_position, sample.pan_spread);
                             let entry = AudioClipOnDisk {
                                 name: String::from(&sample.name),
-                                path: String::from(path.to_str().unwrap()),
+                                path: sample.path.as_ref().map(resolve),
+                                variants: sample.variants.iter().map(resolve).collect(),
+                                selection: sample.selection,
+                                volume_range: sample.volume_range,
+                                pitch_range: sample.pitch_range,
                                 volume,
                                 pan_position: sample.pan_position,
                                 pan_spread: sample.pan_spread,
+                                weight: sample.weight,
+                                tags: sample.tags.clone(),
+                                start_offset: sample.start_offset,
+                                loop_start: sample.loop_start,
+                                loop_end: sample.loop_end,
+                                loop_count: sample.loop_count,
+                                loop_crossfade: sample.loop_crossfade,
+                                rate: sample.rate,
+                                pitch: sample.pitch,
+                                filter: sample.filter.clone(),
+                                reverb_send: sample.reverb_send,
+                                preload: sample.preload,
+                                selector: VariantSelector::default(),
                             };
                             debug!("Created sample bank entry OK: {:?}", entry);
                             entry
                         })
                         .collect();
-                    // let scenes = bank.scenes;
-                    // SoundBank { clips, scenes }
-                    SoundBank { clips }
+                    for entry in &bank.schedule {
+                        if let Err(e) = entry.range.validate() {
+                            panic!("Invalid schedule entry {:?}: {}", entry, e);
+                        }
+                        if !bank.scenes.iter().any(|scene| scene.name == entry.scene) {
+                            panic!("Schedule entry refers to unknown scene \"{}\"", entry.scene);
+                        }
+                    }
+                    for quiet in &bank.quiet_hours {
+                        if let Err(e) = quiet.range.validate() {
+                            panic!("Invalid quiet hours {:?}: {}", quiet, e);
+                        }
+                    }
+                    SoundBank {
+                        clips,
+                        ducking: bank.ducking,
+                        tempo: bank.tempo,
+                        scenes: bank.scenes,
+                        schedule: bank.schedule,
+                        quiet_hours: bank.quiet_hours,
+                    }
                 }
                 Err(e) => {
                     panic!("Failed to parse sample bank JSON: {}", e);
@@ -106,7 +263,27 @@ impl SoundBank {
         &self.clips
     }
 
-    // pub fn scenes(&self) -> &Vec<Scene> {
-    //     &self.scenes
-    // }
+    pub fn clips_mut(&mut self) -> &mut Vec<AudioClipOnDisk> {
+        &mut self.clips
+    }
+
+    pub fn ducking_rules(&self) -> &[DuckingRule] {
+        &self.ducking
+    }
+
+    pub fn tempo(&self) -> Option<&TempoSettings> {
+        self.tempo.as_ref()
+    }
+
+    pub fn scenes(&self) -> &Vec<Scene> {
+        &self.scenes
+    }
+
+    pub fn schedule(&self) -> &[ScheduleEntry] {
+        &self.schedule
+    }
+
+    pub fn quiet_hours(&self) -> &[QuietHours] {
+        &self.quiet_hours
+    }
 }
@@ -3,22 +3,38 @@ use clap::Parser;
 use env_logger::Env;
 use log::*;
 
-use rodio::{cpal::traits::HostTrait, DeviceTrait, OutputStream};
 use std::time::Duration;
 use ui::render_gui;
 
 use settings::Cli;
 
-use crate::model::Model;
+use crate::{model::Model, output::open_output};
 
+mod cache;
+mod clock;
+mod devices;
+mod dsp;
+mod ducking;
+mod generative;
 mod loader;
+mod mixer;
 mod model;
+mod output;
 mod panning;
+mod picker;
 mod playback;
+mod recorder;
+mod region;
 mod remote_control;
+mod render;
+mod scheduler;
+mod sequence;
 mod settings;
+mod tempo;
+mod timetable;
 mod ui;
 mod utils;
+mod variants;
 
 fn main() {
     let cli = Cli::parse();
@@ -29,55 +45,30 @@ fn main() {
         .filter_module("symphonia_bundle_mp3", log::LevelFilter::Warn)
         .init();
 
-    let host = rodio::cpal::default_host();
-    let devices = host
-        .output_devices()
-        .expect("failed to retrieve host audio devices");
-
-    let device = match &cli.preferred_output_device {
-        None => host
-            .default_output_device()
-            .expect("failed to get default output device"),
-        Some(preferred_name) => devices
-            .enumerate()
-            .find(|(i, cpal_device)| {
-                let rodio_device: &rodio::Device = cpal_device;
-
-                let channels = rodio_device.default_output_config().unwrap().channels();
-
-                let name = rodio_device.name().unwrap_or(String::from("unknown"));
-
-                info!(
-                    "Device #{}: \"{}\" with x{} output channels",
-                    i, name, channels
-                );
-
-                &name == preferred_name
-            })
-            .map(|(_usize, device)| device)
-            .unwrap_or_else(|| {
-                panic!(
-                    "failed to find device by preferred name \"{}\"",
-                    preferred_name
-                )
-            }),
-    };
+    if cli.list_devices {
+        devices::print_devices(cli.list_devices_json);
+        return;
+    }
 
-    match device.name() {
-        Err(_) => warn!("Device was set, but failed to retrieve name"),
-        Ok(name) => info!("Device was set; name \"{}\"", name),
-    };
+    if let Some(timeline) = &cli.render_timeline {
+        if let Err(e) = render::render_to_file(&cli, timeline) {
+            error!("Render failed: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let (_output_stream, stream_handle) =
-        OutputStream::try_from_device(&device).expect("failed to open device");
+    let (output, mixer_handle) = open_output(&cli);
+    let channels = mixer_handle.channels();
 
     let mut model = Model::new(
         &cli,
-        stream_handle,
+        mixer_handle,
         match cli.output_channels {
             Some(c) => c,
-            None => device.default_output_config().unwrap().channels(),
+            None => channels,
         },
+        output,
     );
 
     if cli.headless_mode {
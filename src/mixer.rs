@@ -0,0 +1,516 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use log::{debug, error};
+use rodio::Source;
+use rtrb::Producer;
+
+use crate::dsp::reverb::{Reverb, ReverbUpdate};
+
+/// Time over which a change of gain is spread, to avoid "zipper" noise
+const GAIN_RAMP: Duration = Duration::from_millis(20);
+
+/// Voices the mixer has room for before its list needs to grow
+const VOICE_CAPACITY: usize = 256;
+
+/// Sent from the Model to the Mixer (on the audio thread). Voices are identified by
+/// clip ID.
+pub enum MixerCommand {
+    Play(Box<Voice>),
+    /// Change the level (smoothed over a few milliseconds)
+    SetGain(usize, f32),
+    /// Fade out over exactly the given duration, then stop
+    FadeOut(usize, Duration),
+    Pause(usize),
+    Resume(usize),
+    Stop(usize),
+    Reverb(ReverbUpdate),
+    /// Change the level of the whole output mix (smoothed like SetGain), e.g. to cap
+    /// the volume during quiet hours
+    MasterGain(f32),
+    /// Copy the output mix (interleaved) into the given ring buffer, which is read on
+    /// another thread, e.g. for recording. Frames that do not fit are left out.
+    StartTap(Producer<f32>),
+    StopTap,
+}
+
+/// Written by the Mixer, read by the Model
+#[derive(Default)]
+pub struct VoiceStatus {
+    finished: AtomicBool,
+    frames_played: AtomicU64,
+}
+
+impl VoiceStatus {
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// Frames actually played, i.e. not counting any start delay or time paused
+    pub fn frames_played(&self) -> u64 {
+        self.frames_played.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that moves linearly towards a target, reaching it after an exact number
+/// of frames
+struct Ramp {
+    value: f32,
+    target: f32,
+    step: f32,
+    frames_left: u64,
+}
+
+impl Ramp {
+    fn new(value: f32) -> Self {
+        Ramp {
+            value,
+            target: value,
+            step: 0.,
+            frames_left: 0,
+        }
+    }
+
+    fn set(&mut self, target: f32, frames: u64) {
+        self.target = target;
+        self.frames_left = frames;
+        if frames == 0 {
+            self.value = target;
+        } else {
+            self.step = (target - self.value) / frames as f32;
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.frames_left == 0
+    }
+
+    fn next(&mut self) -> f32 {
+        match self.frames_left {
+            0 => {}
+            1 => {
+                self.value = self.target;
+                self.frames_left = 0;
+            }
+            _ => {
+                self.value += self.step;
+                self.frames_left -= 1;
+            }
+        }
+        self.value
+    }
+}
+
+fn frames_in(duration: Duration, sample_rate: u32) -> u64 {
+    (duration.as_secs_f64() * sample_rate as f64).round() as u64
+}
+
+/// A single clip playback, as handled by the Mixer
+pub struct Voice {
+    id: usize,
+    /// Must already be in the output format
+    source: Box<dyn Source<Item = f32> + Send>,
+    status: Arc<VoiceStatus>,
+    /// Frames of silence still to go before the source starts
+    delay_frames: u64,
+    /// Level, e.g. clip volume and ducking
+    gain: Ramp,
+    /// Fade in and out, from 0 to 1
+    envelope: Ramp,
+    releasing: bool,
+    reverb_send: f32,
+    paused: bool,
+}
+
+impl Voice {
+    pub fn new(
+        id: usize,
+        source: Box<dyn Source<Item = f32> + Send>,
+        start_delay: Duration,
+        gain: f32,
+        fade_in: Duration,
+        reverb_send: f32,
+    ) -> (Self, Arc<VoiceStatus>) {
+        let status = Arc::new(VoiceStatus::default());
+        let sample_rate = source.sample_rate();
+        let mut envelope = Ramp::new(0.);
+        envelope.set(1., frames_in(fade_in, sample_rate));
+        let voice = Voice {
+            id,
+            source,
+            status: status.clone(),
+            delay_frames: frames_in(start_delay, sample_rate),
+            gain: Ramp::new(gain),
+            envelope,
+            releasing: false,
+            reverb_send,
+            paused: false,
+        };
+        (voice, status)
+    }
+
+    fn fade_out(&mut self, duration: Duration, sample_rate: u32) {
+        self.releasing = true;
+        self.envelope.set(0., frames_in(duration, sample_rate));
+    }
+
+    /// Add one frame to the output and the reverb input; returns false once the source
+    /// has ended
+    fn mix_frame(&mut self, output: &mut [f32], reverb_input: &mut f32) -> bool {
+        if self.paused {
+            return true;
+        }
+        if self.delay_frames > 0 {
+            self.delay_frames -= 1;
+            return true;
+        }
+
+        if self.releasing && self.envelope.is_finished() {
+            return false;
+        }
+
+        let gain = self.gain.next() * self.envelope.next();
+        for out in output.iter_mut() {
+            let Some(sample) = self.source.next() else {
+                return false;
+            };
+            *out += sample * gain;
+            *reverb_input += sample * gain * self.reverb_send;
+        }
+        self.status.frames_played.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl Drop for Voice {
+    fn drop(&mut self) {
+        self.status.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whatever the Mixer has finished with, sent back to be dropped by the Model
+#[allow(dead_code)] // only ever dropped
+enum Retired {
+    Voice(Box<Voice>),
+    Tap(Producer<f32>),
+}
+
+/// Used by the Model to control the Mixer
+#[derive(Clone)]
+pub struct MixerHandle {
+    commands: Sender<MixerCommand>,
+    retired: Arc<Mutex<Receiver<Retired>>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl MixerHandle {
+    pub fn send(&self, command: MixerCommand) {
+        if self.commands.send(command).is_err() {
+            error!("Mixer is no longer running");
+        }
+    }
+
+    /// Drop the voices (and taps) the Mixer has finished with, freeing their buffers
+    /// here rather than on the audio thread; called regularly by the Model
+    pub fn drop_retired(&self) {
+        let retired = self.retired.lock().unwrap();
+        while retired.try_recv().is_ok() {}
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Plays all the voices (and the shared reverb) as a single Source, in the output
+/// format, which never ends.
+///
+/// Commands arrive through a channel, which is only ever read with `try_recv` (which
+/// does not block or take a lock), so the audio thread is never held up by the Model.
+pub struct Mixer {
+    channels: u16,
+    sample_rate: u32,
+    /// Kept boxed as they arrive, so that nothing is freed on the audio thread
+    #[allow(clippy::vec_box)]
+    voices: Vec<Box<Voice>>,
+    commands: Receiver<MixerCommand>,
+    /// Finished voices and taps are sent back, to be dropped by the Model
+    retired: Sender<Retired>,
+    reverb: Reverb,
+    master_gain: Ramp,
+    tap: Option<Producer<f32>>,
+    frame: Vec<f32>,
+    /// Index of the next sample to return from `frame`
+    index: usize,
+}
+
+impl Mixer {
+    pub fn new(channels: u16, sample_rate: u32) -> (Mixer, MixerHandle) {
+        let (tx, rx) = mpsc::channel();
+        let (retired_tx, retired_rx) = mpsc::channel();
+        let channels = channels.max(1);
+        let mixer = Mixer {
+            channels,
+            sample_rate,
+            voices: Vec::with_capacity(VOICE_CAPACITY),
+            commands: rx,
+            retired: retired_tx,
+            reverb: Reverb::new(sample_rate),
+            master_gain: Ramp::new(1.),
+            tap: None,
+            frame: vec![0.; channels as usize],
+            index: channels as usize,
+        };
+        let handle = MixerHandle {
+            commands: tx,
+            retired: Arc::new(Mutex::new(retired_rx)),
+            channels,
+            sample_rate,
+        };
+        (mixer, handle)
+    }
+
+    fn voice_mut(&mut self, id: usize) -> Option<&mut Voice> {
+        self.voices
+            .iter_mut()
+            .find(|v| v.id == id)
+            .map(|v| &mut **v)
+    }
+
+    /// Remove a voice from the mix and hand it back to the Model
+    fn retire(&mut self, index: usize) {
+        let voice = self.voices.swap_remove(index);
+        voice.status.finished.store(true, Ordering::Relaxed);
+        // If the Model has gone, so has any reason to avoid dropping here
+        let _ = self.retired.send(Retired::Voice(voice));
+    }
+
+    /// Stop copying the output mix, handing the tap back to the Model
+    fn retire_tap(&mut self) {
+        if let Some(tap) = self.tap.take() {
+            let _ = self.retired.send(Retired::Tap(tap));
+        }
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                MixerCommand::Play(voice) => self.voices.push(voice),
+                MixerCommand::SetGain(id, gain) => {
+                    let sample_rate = self.sample_rate;
+                    if let Some(voice) = self.voice_mut(id) {
+                        voice.gain.set(gain, frames_in(GAIN_RAMP, sample_rate));
+                    }
+                }
+                MixerCommand::FadeOut(id, duration) => {
+                    let sample_rate = self.sample_rate;
+                    if let Some(voice) = self.voice_mut(id) {
+                        voice.fade_out(duration, sample_rate);
+                    }
+                }
+                MixerCommand::Pause(id) => {
+                    if let Some(voice) = self.voice_mut(id) {
+                        voice.paused = true;
+                    }
+                }
+                MixerCommand::Resume(id) => {
+                    if let Some(voice) = self.voice_mut(id) {
+                        voice.paused = false;
+                    }
+                }
+                MixerCommand::Stop(id) => {
+                    debug!("Mixer stops voice #{id}");
+                    if let Some(index) = self.voices.iter().position(|v| v.id == id) {
+                        self.retire(index);
+                    }
+                }
+                MixerCommand::Reverb(update) => self.reverb.update(&update),
+                MixerCommand::MasterGain(gain) => {
+                    self.master_gain
+                        .set(gain, frames_in(GAIN_RAMP, self.sample_rate));
+                }
+                MixerCommand::StartTap(tap) => {
+                    self.retire_tap();
+                    self.tap = Some(tap);
+                }
+                MixerCommand::StopTap => self.retire_tap(),
+            }
+        }
+    }
+
+    fn mix_frame(&mut self) {
+        self.handle_commands();
+
+        self.frame.fill(0.);
+        let mut reverb_input = 0.;
+        let mut index = 0;
+        while index < self.voices.len() {
+            if self.voices[index].mix_frame(&mut self.frame, &mut reverb_input) {
+                index += 1;
+            } else {
+                self.retire(index);
+            }
+        }
+
+        let [left, right] = self.reverb.process(reverb_input / self.channels as f32);
+        match self.frame.len() {
+            1 => self.frame[0] += (left + right) / 2.,
+            _ => {
+                for (i, sample) in self.frame.iter_mut().enumerate() {
+                    *sample += if i % 2 == 0 { left } else { right };
+                }
+            }
+        }
+        let master_gain = self.master_gain.next();
+        for sample in self.frame.iter_mut() {
+            *sample *= master_gain;
+        }
+
+        if let Some(tap) = &mut self.tap {
+            if tap.is_abandoned() {
+                self.retire_tap();
+            } else if let Ok(chunk) = tap.write_chunk_uninit(self.frame.len()) {
+                // Only whole frames, so that the channels never get out of step
+                chunk.fill_from_iter(self.frame.iter().copied());
+            }
+        }
+    }
+}
+
+impl Iterator for Mixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index == self.frame.len() {
+            self.mix_frame();
+            self.index = 0;
+        }
+        let sample = self.frame[self.index];
+        self.index += 1;
+        Some(sample)
+    }
+}
+
+impl Source for Mixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+fn constant_voice(id: usize, delay: Duration) -> (Box<Voice>, Arc<VoiceStatus>) {
+    let source = rodio::buffer::SamplesBuffer::new(2, 1000, vec![1f32; 200]);
+    let (voice, status) = Voice::new(id, Box::new(source), delay, 0., Duration::ZERO, 0.);
+    (Box::new(voice), status)
+}
+
+#[test]
+fn gain_ramps_smoothly() {
+    let (mut mixer, handle) = Mixer::new(2, 1000);
+    let (voice, _status) = constant_voice(0, Duration::ZERO);
+    handle.send(MixerCommand::Play(voice));
+    handle.send(MixerCommand::SetGain(0, 1.));
+    let output: Vec<f32> = (&mut mixer).take(2 * 30).step_by(2).collect();
+    // 20ms ramp at 1kHz, i.e. over 20 frames
+    assert!(output.windows(2).take(19).all(|w| w[1] > w[0]));
+    assert!((output[19] - 1.).abs() < 1e-4);
+    assert!((output[25] - 1.).abs() < 1e-4);
+}
+
+#[test]
+fn start_delay_is_sample_accurate() {
+    let (mut mixer, handle) = Mixer::new(2, 1000);
+    let (voice, status) = constant_voice(0, Duration::from_millis(10));
+    handle.send(MixerCommand::Play(voice));
+    handle.send(MixerCommand::SetGain(0, 1.));
+    let output: Vec<f32> = (&mut mixer).take(2 * 11).collect();
+    assert!(output[..20].iter().all(|s| *s == 0.));
+    assert!(output[20] > 0.);
+    assert_eq!(status.frames_played(), 1);
+
+    // The source has 100 frames
+    let _ = (&mut mixer).take(2 * 100).count();
+    assert!(status.is_finished());
+}
+
+#[test]
+fn fade_out_takes_exactly_as_long_as_requested() {
+    let (mut mixer, handle) = Mixer::new(1, 1000);
+    let source = rodio::buffer::SamplesBuffer::new(1, 1000, vec![1f32; 1000]);
+    let (voice, status) = Voice::new(0, Box::new(source), Duration::ZERO, 1., Duration::ZERO, 0.);
+    handle.send(MixerCommand::Play(Box::new(voice)));
+    handle.send(MixerCommand::FadeOut(0, Duration::from_millis(50)));
+    let output: Vec<f32> = (&mut mixer).take(60).collect();
+    assert!(output.windows(2).take(49).all(|w| w[1] < w[0]));
+    assert!(output[49].abs() < 1e-4);
+    assert_eq!(status.frames_played(), 50);
+    assert!(status.is_finished());
+}
+
+#[test]
+fn tap_receives_every_output_sample() {
+    let (mut mixer, handle) = Mixer::new(2, 1000);
+    let (voice, _status) = constant_voice(0, Duration::ZERO);
+    handle.send(MixerCommand::Play(voice));
+    handle.send(MixerCommand::SetGain(0, 1.));
+    let (producer, mut consumer) = rtrb::RingBuffer::new(2 * 3000);
+    handle.send(MixerCommand::StartTap(producer));
+    let output: Vec<f32> = (&mut mixer).take(2 * 3000).collect();
+    handle.send(MixerCommand::StopTap);
+    let _ = mixer.next();
+    let tapped: Vec<f32> = consumer
+        .read_chunk(consumer.slots())
+        .unwrap()
+        .into_iter()
+        .collect();
+    assert_eq!(tapped, output);
+    // The tap is handed back, to be dropped by the Model rather than on the audio thread
+    assert!(!consumer.is_abandoned());
+    handle.drop_retired();
+    assert!(consumer.is_abandoned());
+}
+
+#[test]
+fn master_gain_scales_the_whole_mix() {
+    let (mut mixer, handle) = Mixer::new(2, 1000);
+    let (voice, _status) = constant_voice(0, Duration::ZERO);
+    handle.send(MixerCommand::Play(voice));
+    handle.send(MixerCommand::SetGain(0, 1.));
+    handle.send(MixerCommand::MasterGain(0.25));
+    let output: Vec<f32> = (&mut mixer).take(2 * 30).step_by(2).collect();
+    assert!((output[25] - 0.25).abs() < 1e-4);
+}
+
+#[test]
+fn finished_voices_are_returned_to_the_model() {
+    let (mut mixer, handle) = Mixer::new(2, 1000);
+    let (voice, status) = constant_voice(0, Duration::ZERO);
+    handle.send(MixerCommand::Play(voice));
+    let _ = (&mut mixer).take(2 * 101).count();
+    assert!(status.is_finished());
+    assert!(mixer.voices.is_empty());
+    assert!(handle.retired.lock().unwrap().try_recv().is_ok());
+}
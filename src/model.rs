@@ -1,36 +1,52 @@
+use anyhow::anyhow;
 use log::*;
 use std::{
     ops::Deref,
-    path::Path,
+    path::{Path, PathBuf},
     sync::mpsc::{self, Receiver},
     thread::{self, JoinHandle},
     time::{Duration, SystemTime},
 };
 
-use rodio::OutputStreamHandle;
 use tether_agent::{TetherAgent, TetherAgentOptionsBuilder};
 
 use crate::{
+    cache::ClipCache,
+    clock,
+    devices::output_device_names,
+    dsp::reverb::ReverbUpdate,
+    ducking::{combined_gain, Ducker},
+    generative::GenerativeLayer,
     loader::SoundBank,
-    playback::{ClipWithSink, PanWithRange},
+    mixer::{MixerCommand, MixerHandle},
+    output::{AudioOutput, OutputChange, HANDOVER_FADE},
+    picker::{ClipPicker, RandomPickOptions},
+    playback::{PanWithRange, PlaybackOverrides, PlaybackPhase, PlayingClip},
+    recorder::{Recorder, RecordingSettings},
     remote_control::{
         publish::SoundscapeEvent,
         receive::{GlobalControlMode, Instruction, ScenePickMode},
         RemoteControl,
     },
+    scheduler::Scheduler,
+    sequence::ActiveSequence,
     settings::Cli,
-    utils::{optional_ms_to_duration, pick_random_clip},
+    tempo::{Quantize, TempoClock, TempoSettings},
+    timetable::{Timetable, TimetableChange},
+    utils::optional_ms_to_duration,
 };
 
 pub enum ActionQueueItem {
     /// Start playback: name, optional volume override, optional fade duration, should_loop,
-    /// optional pan position with range
+    /// optional pan position with range, optional quantisation, start/loop overrides
     Play(
         String,
         Option<f32>,
         Option<Duration>,
         bool,
         Option<PanWithRange>,
+        Option<Quantize>,
+        PlaybackOverrides,
     ),
     /// Stop/fade out: id in currently_playing Vec, optional fade duration
     Stop(usize, Option<Duration>),
@@ -48,10 +64,30 @@ pub struct Model {
     _request_loop_handle: JoinHandle<()>,
     // pub request_channel: (Sender<()>, Receiver<()>),
     pub request_rx: Receiver<()>,
-    pub output_stream_handle: OutputStreamHandle,
+    pub mixer: MixerHandle,
+    recorder: Option<Recorder>,
+    recording_dir: PathBuf,
     pub output_channels_used: u16,
+    /// As given on the command line; otherwise every channel of the output is used
+    requested_output_channels: Option<u16>,
+    pub output: AudioOutput,
+    available_output_devices: Option<Vec<String>>,
+    /// Every reverb change so far, to apply again to a new mixer
+    reverb_settings: ReverbUpdate,
     pub sound_bank: SoundBank,
-    pub clips_playing: Vec<ClipWithSink>,
+    clip_cache: ClipCache,
+    pub clips_playing: Vec<PlayingClip>,
+    pub duckers: Vec<Ducker>,
+    pub generative_layer: Option<GenerativeLayer>,
+    pub sequence: Option<ActiveSequence>,
+    pub tempo: Option<TempoClock>,
+    pub scheduler: Scheduler,
+    pub timetable: Timetable,
+    last_timetable_check: Option<SystemTime>,
+    pub volume_cap: Option<f32>,
+    next_clip_id: usize,
+    picker: ClipPicker,
+    last_progress_check: SystemTime,
     // clips_playing: Vec<CurrentlyPlayingClip>,
     // duration_range: [FadeDuration; 2],
     pub action_queue: Vec<ActionQueueItem>,
@@ -65,13 +101,34 @@ pub struct Model {
 impl Model {
     pub fn new(
         cli: &Cli,
-        output_stream_handle: OutputStreamHandle,
+        mixer: MixerHandle,
         output_channels_used: u16,
+        output: AudioOutput,
     ) -> Model {
         let sound_bank = SoundBank::new(Path::new(match &cli.sample_bank_path {
             None => "soundbank-demo.json",
             Some(p) => p.deref(),
         }));
+        let mut clip_cache = ClipCache::new(
+            cli.preload_budget_mb.saturating_mul(1024 * 1024),
+            cli.preload_all,
+        );
+        for clip in sound_bank.clips() {
+            if clip_cache.should_preload(clip.preload()) {
+                for path in clip.paths() {
+                    if let Err(e) = clip_cache.load(path) {
+                        warn!("Could not preload \"{}\": {}", path, e);
+                    }
+                }
+            }
+        }
+        if clip_cache.used_bytes() > 0 {
+            info!(
+                "Preloaded clips use {} MB",
+                clip_cache.used_bytes() / (1024 * 1024)
+            );
+        }
+
         let tether_options = TetherAgentOptionsBuilder::new("soundscape").auto_connect(false);
         let mut tether = if cli.tether_disable {
             warn!("Tether connection disabled");
@@ -99,23 +156,55 @@ impl Model {
             ))
         };
 
+        let duckers = sound_bank
+            .ducking_rules()
+            .iter()
+            .map(|rule| Ducker::new(rule.clone()))
+            .collect();
+
+        let tempo = sound_bank.tempo().map(TempoClock::new);
+        let timetable = Timetable::new(
+            sound_bank.schedule().to_vec(),
+            sound_bank.quiet_hours().to_vec(),
+        );
+
         let (tx, rx) = mpsc::channel();
 
         let update_interval = cli.update_interval; // clone for move
 
-        let request_loop_handle = thread::spawn(move || loop {
-            tx.send(()).expect("failed to send via channel");
-            thread::sleep(Duration::from_millis(update_interval));
+        let request_loop_handle = thread::spawn(move || {
+            // Ends once the Model has gone
+            while tx.send(()).is_ok() {
+                thread::sleep(Duration::from_millis(update_interval));
+            }
         });
 
         Model {
             // request_channel: (tx, rx),
             request_rx: rx,
             _request_loop_handle: request_loop_handle,
-            output_stream_handle,
+            mixer,
+            recorder: None,
+            recording_dir: PathBuf::from(&cli.recording_dir),
             output_channels_used,
+            requested_output_channels: cli.output_channels,
+            output,
+            available_output_devices: None,
+            reverb_settings: ReverbUpdate::default(),
             sound_bank,
+            clip_cache,
             clips_playing: Vec::new(),
+            duckers,
+            generative_layer: None,
+            sequence: None,
+            tempo,
+            scheduler: Scheduler::default(),
+            timetable,
+            last_timetable_check: None,
+            volume_cap: None,
+            next_clip_id: 0,
+            picker: ClipPicker::new(cli.seed),
+            last_progress_check: clock::now(),
             action_queue: Vec::new(),
             // last_state_publish: std::time::SystemTime::now(),
             tether,
@@ -132,6 +221,15 @@ impl Model {
     }
 
     pub fn check_progress(&mut self) {
+        let elapsed = clock::since(self.last_progress_check);
+        self.last_progress_check = clock::now();
+        if self.output.is_lost() {
+            // Nothing is playing, and every voice has gone with the old mixer; clips
+            // carry on from where they were once the output is back
+            return;
+        }
+        self.update_ducking(elapsed);
+
         for clip in &mut self.clips_playing {
             clip.update_progress();
         }
@@ -142,15 +240,30 @@ impl Model {
             .find(|(_i, x)| x.is_completed());
         if let Some((i, x)) = completed {
             debug!("Removing clip index {}", i);
-            let clip_name = x.name();
-            if let Some(remote) = &self.remote_control {
-                remote.publish_event(SoundscapeEvent::ClipEnded(clip_name.into()), &self.tether);
-                self.message_stats.last_events_message = Some(SystemTime::now());
-            }
+            let clip_name = String::from(x.name());
+            self.publish_event(SoundscapeEvent::ClipEnded(clip_name));
             self.clips_playing.remove(i);
         }
     }
 
+    /// Ducking rules are triggered by any matching clip that is not already fading out
+    fn update_ducking(&mut self, elapsed: Duration) {
+        if self.duckers.is_empty() {
+            return;
+        }
+        for ducker in &mut self.duckers {
+            let triggered = self.clips_playing.iter().any(|clip| {
+                clip.has_tag(&ducker.rule().trigger)
+                    && !matches!(clip.phase(), PlaybackPhase::Release(..))
+            });
+            ducker.update(triggered, elapsed);
+        }
+        for clip in &mut self.clips_playing {
+            clip.set_ducking_gain(combined_gain(&self.duckers, clip.tags()));
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn play_one_clip(
         &mut self,
         clip_name: &str,
@@ -158,193 +271,662 @@ impl Model {
         volume_override: Option<f32>,
         fade: Option<Duration>,
         override_panning: Option<PanWithRange>,
-    ) {
+        start_delay: Option<Duration>,
+        overrides: &PlaybackOverrides,
+    ) -> Option<usize> {
         if let Some(sample) = self
             .sound_bank
-            .clips()
-            .iter()
+            .clips_mut()
+            .iter_mut()
             .find(|x| x.name() == clip_name)
         {
-            let clip_with_sink = ClipWithSink::new(
-                self.clips_playing.len(),
+            let id = self.next_clip_id;
+            self.next_clip_id += 1;
+            let ducking_gain = combined_gain(&self.duckers, sample.tags());
+            let clip_with_sink = PlayingClip::new(
+                id,
                 sample,
                 should_loop,
                 volume_override,
                 fade,
                 override_panning,
-                &self.output_stream_handle,
+                start_delay,
+                overrides,
+                ducking_gain,
+                &self.mixer,
                 self.output_channels_used,
+                &mut self.clip_cache,
+                self.picker.rng(),
             );
             self.clips_playing.push(clip_with_sink);
+            Some(id)
         } else {
             error!("Failed to find clip in bank with name, {}", clip_name);
+            None
         }
     }
 
-    pub fn internal_update(&mut self) {
-        if self.request_rx.try_recv().is_ok() {
-            self.check_progress();
+    /// Resolve relative weights for a random pick: from the message if provided,
+    /// otherwise from the Sample Bank
+    fn pick_weights(
+        &self,
+        clip_names: &[String],
+        options: &RandomPickOptions,
+    ) -> anyhow::Result<Vec<f32>> {
+        match &options.weights {
+            Some(weights) if weights.len() != clip_names.len() => Err(anyhow!(
+                "Got {} weights for {} clip names",
+                weights.len(),
+                clip_names.len()
+            )),
+            Some(weights) => Ok(weights.clone()),
+            None => Ok(clip_names
+                .iter()
+                .map(|name| {
+                    self.sound_bank
+                        .clips()
+                        .iter()
+                        .find(|c| c.name().eq_ignore_ascii_case(name))
+                        .map(|c| c.weight())
+                        .unwrap_or(1.0)
+                })
+                .collect()),
         }
+    }
 
-        // Parse any remote control messages, which may generate CommandQueue items
-        if let Some(remote_control) = &mut self.remote_control {
-            while let Some((topic, message)) = self.tether.check_messages() {
-                match remote_control.parse_instructions(&topic, &message) {
-                    Ok(Instruction::Add(clip_name, should_loop, volume, fade_ms, panning)) => {
-                        self.message_stats.last_clip_message = Some(SystemTime::now());
-
-                        self.action_queue.push(ActionQueueItem::Play(
-                            clip_name,
-                            volume,
-                            fade_ms.map(Duration::from_millis),
-                            should_loop,
-                            panning,
-                        ));
-                    }
-                    Ok(Instruction::Remove(clip_name, fade_ms)) => {
-                        self.message_stats.last_clip_message = Some(SystemTime::now());
-
-                        for clip in self
-                            .clips_playing
-                            .iter_mut()
-                            .filter(|x| x.name() == clip_name)
-                        {
-                            // if let Some(ms) = fade_ms {
-                            //     clip.fade_out(Duration::from_millis(ms));
-                            // } else {
-                            //     clip.stop();
-                            // }
-                            self.action_queue.push(ActionQueueItem::Stop(
-                                clip.id(),
-                                fade_ms.map(Duration::from_millis),
-                            ))
-                        }
-                    }
-                    Ok(Instruction::Scene(scene_pick_mode, clip_names, fade_ms)) => {
-                        self.message_stats.last_scene_message = Some(SystemTime::now());
-                        match scene_pick_mode {
-                            ScenePickMode::OnceAll => {
-                                if clip_names.is_empty() {
-                                    debug!("Empty scene list; stop all currently playing");
-                                    for clip in &self.clips_playing {
-                                        self.action_queue.push(ActionQueueItem::Stop(
-                                            clip.id(),
-                                            optional_ms_to_duration(fade_ms),
-                                        ))
-                                    }
-                                } else {
-                                    for name in clip_names {
-                                        self.action_queue.push(ActionQueueItem::Play(
-                                            name,
-                                            None,
-                                            fade_ms.map(Duration::from_millis),
-                                            false,
-                                            None,
-                                        ));
-                                    }
-                                }
-                            }
-                            ScenePickMode::LoopAll => {
-                                // TODO: check for
-                                // - empty list (stop all)
-                                // - clips already playing (and LOOPING) (do not add)
-                                if clip_names.is_empty() {
-                                    debug!("Empty scene list; stop all currently playing that are looping");
-                                    for clip in &self.clips_playing {
-                                        self.action_queue.push(ActionQueueItem::Stop(
-                                            clip.id(),
-                                            optional_ms_to_duration(fade_ms),
-                                        ))
-                                    }
-                                } else {
-                                    let to_add = clip_names.iter().filter(|candidate| {
-                                        Option::is_none(&self.clips_playing.iter().find(
-                                            |playing| {
-                                                playing.name().eq_ignore_ascii_case(candidate)
-                                            },
-                                        ))
-                                    });
-                                    let to_remove = self.clips_playing.iter().filter(|playing| {
-                                        Option::is_none(&clip_names.iter().find(|requested| {
-                                            requested.eq_ignore_ascii_case(playing.name())
-                                        }))
-                                    });
-                                    for name in to_add {
-                                        self.action_queue.push(ActionQueueItem::Play(
-                                            name.into(),
-                                            None,
-                                            optional_ms_to_duration(fade_ms),
-                                            true,
-                                            None,
-                                        ));
-                                    }
-                                    for clip in to_remove {
-                                        self.action_queue.push(ActionQueueItem::Stop(
-                                            clip.id(),
-                                            optional_ms_to_duration(fade_ms),
-                                        ));
-                                    }
-                                }
+    fn pick_random_clip(
+        &mut self,
+        clip_names: &[String],
+        options: &RandomPickOptions,
+    ) -> anyhow::Result<String> {
+        let weights = self.pick_weights(clip_names, options)?;
+        self.picker.pick(
+            clip_names,
+            &weights,
+            options.avoid_repeat.unwrap_or_default(),
+        )
+    }
+
+    fn publish_event(&mut self, event: SoundscapeEvent) {
+        if let Some(remote) = &self.remote_control {
+            remote.publish_event(event, &self.tether);
+            self.message_stats.last_events_message = Some(clock::now());
+        }
+    }
+
+    fn report_error(&mut self, e: anyhow::Error) {
+        error!("{e}");
+        self.publish_event(SoundscapeEvent::Error(e.to_string()));
+    }
+
+    /// Time to wait for the requested grid point, if any; quantisation is ignored
+    /// if no tempo has been set
+    fn quantize_delay(&self, quantize: Option<Quantize>) -> Option<Duration> {
+        match (quantize, &self.tempo) {
+            (Some(q), Some(clock)) => Some(clock.time_until_next(q)),
+            (Some(_), None) => {
+                warn!("Quantisation requested, but no tempo has been set; start immediately");
+                None
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Note the arrival of a message for the stats, then carry it out. Scheduled
+    /// instructions count when they are received, not when they become due.
+    pub fn receive_instruction(&mut self, instruction: Instruction) {
+        self.note_received(&instruction);
+        self.handle_instruction(instruction);
+    }
+
+    fn note_received(&mut self, instruction: &Instruction) {
+        let now = Some(clock::now());
+        match instruction {
+            Instruction::Add(..)
+            | Instruction::Remove(..)
+            | Instruction::SetRate(..)
+            | Instruction::SetFilter(..) => self.message_stats.last_clip_message = now,
+            Instruction::Scene(..) => self.message_stats.last_scene_message = now,
+            Instruction::Global(_) => self.message_stats.last_global_control_message = now,
+            Instruction::Scheduled(_, _, instruction) => self.note_received(instruction),
+            Instruction::Cues(instructions) => {
+                for instruction in instructions {
+                    self.note_received(instruction);
+                }
+            }
+        }
+    }
+
+    pub fn handle_instruction(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Add(
+                clip_name,
+                should_loop,
+                volume,
+                fade_ms,
+                panning,
+                quantize,
+                overrides,
+            ) => {
+                self.action_queue.push(ActionQueueItem::Play(
+                    clip_name,
+                    volume,
+                    fade_ms.map(Duration::from_millis),
+                    should_loop,
+                    panning,
+                    quantize,
+                    overrides,
+                ));
+            }
+            Instruction::Remove(clip_name, fade_ms) => {
+                for clip in self
+                    .clips_playing
+                    .iter_mut()
+                    .filter(|x| x.name() == clip_name)
+                {
+                    // if let Some(ms) = fade_ms {
+                    //     clip.fade_out(Duration::from_millis(ms));
+                    // } else {
+                    //     clip.stop();
+                    // }
+                    self.action_queue.push(ActionQueueItem::Stop(
+                        clip.id(),
+                        fade_ms.map(Duration::from_millis),
+                    ))
+                }
+            }
+            Instruction::SetRate(clip_name, rate, ramp_ms) => {
+                for clip in self
+                    .clips_playing
+                    .iter_mut()
+                    .filter(|x| x.name() == clip_name)
+                {
+                    clip.set_rate(rate, optional_ms_to_duration(ramp_ms));
+                }
+            }
+            Instruction::SetFilter(clip_name, update, ramp_ms) => {
+                for clip in self
+                    .clips_playing
+                    .iter_mut()
+                    .filter(|x| x.name() == clip_name)
+                {
+                    clip.set_filter(&update, optional_ms_to_duration(ramp_ms));
+                }
+            }
+            Instruction::Scene(scene_pick_mode, clip_names, fade_ms, quantize) => {
+                // Any new scene replaces a running generative layer or sequence
+                self.generative_layer = None;
+                self.sequence = None;
+                match scene_pick_mode {
+                    ScenePickMode::OnceAll => {
+                        if clip_names.is_empty() {
+                            debug!("Empty scene list; stop all currently playing");
+                            for clip in &self.clips_playing {
+                                self.action_queue.push(ActionQueueItem::Stop(
+                                    clip.id(),
+                                    optional_ms_to_duration(fade_ms),
+                                ))
                             }
-                            ScenePickMode::OnceRandomSinglePick => {
-                                let pick_name = pick_random_clip(clip_names);
+                        } else {
+                            for name in clip_names {
                                 self.action_queue.push(ActionQueueItem::Play(
-                                    pick_name,
+                                    name,
                                     None,
-                                    optional_ms_to_duration(fade_ms),
+                                    fade_ms.map(Duration::from_millis),
                                     false,
                                     None,
+                                    quantize,
+                                    PlaybackOverrides::default(),
                                 ));
                             }
                         }
                     }
-                    Ok(Instruction::Global(global_control_mode)) => match global_control_mode {
-                        GlobalControlMode::PauseAll() => {
-                            self.message_stats.last_global_control_message =
-                                Some(SystemTime::now());
-                            for clip in &mut self.clips_playing {
-                                clip.pause();
+                    ScenePickMode::LoopAll => {
+                        // TODO: check for
+                        // - empty list (stop all)
+                        // - clips already playing (and LOOPING) (do not add)
+                        if clip_names.is_empty() {
+                            debug!("Empty scene list; stop all currently playing that are looping");
+                            for clip in &self.clips_playing {
+                                self.action_queue.push(ActionQueueItem::Stop(
+                                    clip.id(),
+                                    optional_ms_to_duration(fade_ms),
+                                ))
                             }
-                        }
-                        GlobalControlMode::ResumeAll() => {
-                            self.message_stats.last_global_control_message =
-                                Some(SystemTime::now());
-                            for clip in &mut self.clips_playing {
-                                clip.resume();
+                        } else {
+                            let to_add =
+                                clip_names.iter().filter(|candidate| {
+                                    Option::is_none(&self.clips_playing.iter().find(|playing| {
+                                        playing.name().eq_ignore_ascii_case(candidate)
+                                    }))
+                                });
+                            let to_remove = self.clips_playing.iter().filter(|playing| {
+                                Option::is_none(&clip_names.iter().find(|requested| {
+                                    requested.eq_ignore_ascii_case(playing.name())
+                                }))
+                            });
+                            for name in to_add {
+                                self.action_queue.push(ActionQueueItem::Play(
+                                    name.into(),
+                                    None,
+                                    optional_ms_to_duration(fade_ms),
+                                    true,
+                                    None,
+                                    quantize,
+                                    PlaybackOverrides::default(),
+                                ));
                             }
-                        }
-                        GlobalControlMode::SilenceAll() => {
-                            self.message_stats.last_global_control_message =
-                                Some(SystemTime::now());
-                            for clip in &self.clips_playing {
+                            for clip in to_remove {
                                 self.action_queue.push(ActionQueueItem::Stop(
                                     clip.id(),
-                                    Some(Duration::from_millis(100)),
+                                    optional_ms_to_duration(fade_ms),
                                 ));
                             }
                         }
-                        GlobalControlMode::MasterVolume(volume) => {
-                            self.message_stats.last_global_control_message =
-                                Some(SystemTime::now());
-                            for clip in &mut self.clips_playing {
-                                clip.set_volume(volume);
+                    }
+                    ScenePickMode::OnceRandomSinglePick(options) => {
+                        match self.pick_random_clip(&clip_names, &options) {
+                            Ok(pick_name) => {
+                                self.action_queue.push(ActionQueueItem::Play(
+                                    pick_name,
+                                    None,
+                                    optional_ms_to_duration(fade_ms),
+                                    false,
+                                    None,
+                                    quantize,
+                                    PlaybackOverrides::default(),
+                                ));
                             }
+                            Err(e) => self.report_error(e.context("Random scene rejected")),
+                        }
+                    }
+                    ScenePickMode::RandomInterval(settings) => {
+                        if clip_names.is_empty() {
+                            self.report_error(anyhow!(
+                                "Generative layer rejected: empty list of clips"
+                            ));
+                        } else if let Err(e) = self.pick_weights(&clip_names, &settings.pick) {
+                            self.report_error(e.context("Generative layer rejected"));
+                        } else {
+                            self.generative_layer = Some(GenerativeLayer::new(
+                                clip_names,
+                                settings,
+                                optional_ms_to_duration(fade_ms),
+                                quantize,
+                            ));
+                        }
+                    }
+                    ScenePickMode::Sequence(settings) => {
+                        if clip_names.is_empty() {
+                            self.report_error(anyhow!("Sequence rejected: empty list of clips"));
+                        } else {
+                            self.sequence = Some(ActiveSequence::new(
+                                clip_names,
+                                settings,
+                                optional_ms_to_duration(fade_ms),
+                                quantize,
+                                self.picker.rng(),
+                            ));
+                        }
+                    }
+                }
+            }
+            Instruction::Scheduled(due, schedule_id, instruction) => {
+                debug!("Scheduling instruction with ID {schedule_id:?} for {due:?}");
+                self.scheduler.add(due, schedule_id, *instruction);
+            }
+            Instruction::Cues(instructions) => {
+                for instruction in instructions {
+                    self.handle_instruction(instruction);
+                }
+            }
+            Instruction::Global(global_control_mode) => match global_control_mode {
+                GlobalControlMode::PauseAll() => {
+                    for clip in &mut self.clips_playing {
+                        clip.pause();
+                    }
+                }
+                GlobalControlMode::ResumeAll() => {
+                    for clip in &mut self.clips_playing {
+                        clip.resume();
+                    }
+                }
+                GlobalControlMode::SilenceAll() => {
+                    for clip in &self.clips_playing {
+                        self.action_queue.push(ActionQueueItem::Stop(
+                            clip.id(),
+                            Some(Duration::from_millis(100)),
+                        ));
+                    }
+                }
+                GlobalControlMode::MasterVolume(volume) => {
+                    for clip in &mut self.clips_playing {
+                        clip.set_volume(volume);
+                    }
+                }
+                GlobalControlMode::CancelScheduled(schedule_id) => {
+                    let count = self.scheduler.cancel(schedule_id.as_deref());
+                    info!("Cancelled x{count} scheduled instructions");
+                }
+                GlobalControlMode::Reverb(update) => {
+                    self.reverb_settings.combine(&update);
+                    self.mixer.send(MixerCommand::Reverb(update));
+                }
+                GlobalControlMode::StartRecording(settings) => {
+                    self.start_recording(settings);
+                }
+                GlobalControlMode::StopRecording() => {
+                    self.stop_recording();
+                }
+                GlobalControlMode::SetDevice(device) => {
+                    self.set_output_device(&device);
+                }
+                GlobalControlMode::SetTempo(bpm, beats_per_bar) => match &mut self.tempo {
+                    Some(clock) => clock.set_tempo(bpm, beats_per_bar),
+                    None => {
+                        self.tempo = Some(TempoClock::new(&TempoSettings { bpm, beats_per_bar }))
+                    }
+                },
+            },
+        }
+    }
+
+    pub fn start_recording(&mut self, settings: RecordingSettings) {
+        if self.recorder.is_some() {
+            warn!("Already recording; ignored");
+            return;
+        }
+        match Recorder::start(&self.recording_dir, settings, &self.mixer) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e) => self.report_error(e.context("Failed to start recording")),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        match self.recorder.take() {
+            Some(recorder) => recorder.stop(&self.mixer),
+            None => warn!("Not recording; nothing to stop"),
+        }
+    }
+
+    /// Move playback to another output device, chosen in the same way as
+    /// `--output.device` (e.g. part of its name), once it has opened in the background
+    pub fn set_output_device(&mut self, device: &str) {
+        if let Err(e) = self.output.switch_to(device) {
+            self.report_error(e.context("Failed to switch output device"));
+        }
+    }
+
+    /// Output devices available to switch to, listed once and then kept until refreshed
+    pub fn available_output_devices(&mut self) -> &[String] {
+        self.available_output_devices
+            .get_or_insert_with(output_device_names)
+    }
+
+    pub fn refresh_output_devices(&mut self) {
+        self.available_output_devices = None;
+    }
+
+    /// Carry on playing everything through a new mixer (i.e. a new output stream), each
+    /// clip from where it had got to. Clips already fading out are simply ended. On a
+    /// handover, the old mixer is still playing, and clips cross-fade from one to the
+    /// other.
+    fn move_to_mixer(&mut self, mixer: MixerHandle, handover: bool) {
+        if self.recorder.take().is_some() {
+            warn!("Recording stopped, since the output has changed");
+        }
+        self.mixer = mixer;
+        self.mixer
+            .send(MixerCommand::Reverb(self.reverb_settings.clone()));
+        self.mixer
+            .send(MixerCommand::MasterGain(self.volume_cap.unwrap_or(1.)));
+        self.output_channels_used = self
+            .requested_output_channels
+            .unwrap_or(self.output.info().channels);
+
+        let mut ended = Vec::new();
+        let mut kept = Vec::new();
+        for mut clip in self.clips_playing.drain(..) {
+            if let PlaybackPhase::Release(..) = clip.phase() {
+                ended.push(String::from(clip.name()));
+            } else {
+                clip.restart(
+                    &self.mixer,
+                    self.output_channels_used,
+                    &mut self.clip_cache,
+                    HANDOVER_FADE,
+                    handover,
+                );
+                kept.push(clip);
+            }
+        }
+        info!("Moved x{} clips to the new output", kept.len());
+        self.clips_playing = kept;
+        for clip_name in ended {
+            self.publish_event(SoundscapeEvent::ClipEnded(clip_name));
+        }
+    }
+
+    /// How long the current recording has been running, if any
+    pub fn recording_elapsed(&self) -> Option<Duration> {
+        self.recorder.as_ref().map(Recorder::elapsed)
+    }
+
+    fn update_generative_layer(&mut self) {
+        let Some(layer) = &self.generative_layer else {
+            return;
+        };
+        let currently_playing = self
+            .clips_playing
+            .iter()
+            .filter(|playing| {
+                layer
+                    .clip_names()
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(playing.name()))
+            })
+            .count();
+        let weights = match self.pick_weights(layer.clip_names(), layer.pick_options()) {
+            Ok(weights) => weights,
+            Err(e) => {
+                self.generative_layer = None;
+                self.report_error(e.context("Generative layer stopped"));
+                return;
+            }
+        };
+        if let Some(layer) = &mut self.generative_layer {
+            match layer.poll(currently_playing, &weights, &mut self.picker) {
+                Some(Ok(action)) => self.action_queue.push(action),
+                Some(Err(e)) => self.report_error(e.context("Generative layer pick failed")),
+                None => {}
+            }
+        }
+    }
+
+    /// Hand over to the next clip in the sequence when the current one has completed
+    /// (i.e. has been removed in `check_progress`), or is about to, if overlapping
+    fn update_sequence(&mut self) {
+        let Some(sequence) = &mut self.sequence else {
+            return;
+        };
+
+        if let Some(id) = sequence.current_clip() {
+            let handover = match self.clips_playing.iter().find(|c| c.id() == id) {
+                None => true,
+                Some(clip) => match (sequence.overlap(), clip.remaining()) {
+                    (Some(overlap), Some(remaining)) => remaining <= overlap,
+                    _ => false,
+                },
+            };
+            if handover && !sequence.advance(self.picker.rng()) {
+                debug!("Sequence completed");
+                self.sequence = None;
+                return;
+            }
+        }
+
+        if let Some((index, name)) = sequence.due() {
+            let clip_name = String::from(name);
+            let fade = sequence.fade();
+            let quantize = sequence.quantize();
+            let delay = self.quantize_delay(quantize);
+            let id = self.play_one_clip(
+                &clip_name,
+                false,
+                None,
+                fade,
+                None,
+                delay,
+                &PlaybackOverrides::default(),
+            );
+            if let Some(sequence) = &mut self.sequence {
+                sequence.on_started(id);
+                if id.is_none() && !sequence.advance(self.picker.rng()) {
+                    if sequence.is_unplayable() {
+                        warn!("Stopping the sequence, as none of its clips can be played");
+                    }
+                    self.sequence = None;
+                }
+            }
+            if id.is_some() {
+                self.publish_event(SoundscapeEvent::ClipStarted(clip_name.clone()));
+                self.publish_event(SoundscapeEvent::SequenceStep { index, clip_name });
+            }
+        }
+    }
+
+    /// Check the time-of-day schedule (at most once per second), starting scenes
+    /// and applying quiet hours as necessary
+    fn update_timetable(&mut self) {
+        if self.timetable.is_empty() {
+            return;
+        }
+        if let Some(t) = self.last_timetable_check {
+            if clock::since(t) < Duration::from_secs(1) {
+                return;
+            }
+        }
+        self.last_timetable_check = Some(clock::now());
+
+        let now = clock::local_now();
+
+        let cap = self.timetable.volume_cap(now);
+        if cap != self.volume_cap {
+            info!("Quiet hours volume cap is now {cap:?}");
+            self.volume_cap = cap;
+            self.mixer.send(MixerCommand::MasterGain(cap.unwrap_or(1.)));
+        }
+
+        match self.timetable.update(now) {
+            Some(TimetableChange::Enter(entry)) => {
+                info!("Schedule entry \"{}\" is now active", entry.label());
+                let scene = self
+                    .sound_bank
+                    .scenes()
+                    .iter()
+                    .find(|scene| scene.name == entry.scene)
+                    .cloned();
+                match scene {
+                    Some(scene) => {
+                        let mut message = scene.message;
+                        message.fade_duration = entry.fade_duration.or(message.fade_duration);
+                        match message.into_instruction() {
+                            Ok(instruction) => self.handle_instruction(instruction),
+                            Err(e) => self.report_error(e.context("Scheduled scene rejected")),
                         }
-                    },
+                    }
+                    None => self.report_error(anyhow!("Unknown scheduled scene {}", entry.scene)),
+                }
+            }
+            Some(TimetableChange::Leave(fade_ms)) => {
+                info!("No schedule entry active; stop all");
+                self.handle_instruction(Instruction::Scene(
+                    ScenePickMode::LoopAll,
+                    Vec::new(),
+                    fade_ms,
+                    None,
+                ));
+            }
+            None => {}
+        }
+    }
+
+    pub fn internal_update(&mut self) {
+        if self.request_rx.try_recv().is_ok() {
+            self.check_progress();
+        }
+        self.update();
+    }
+
+    /// Everything in an update except checking playback progress (which is paced
+    /// separately, by the update interval)
+    pub fn update(&mut self) {
+        self.mixer.drop_retired();
+        match self.output.check() {
+            Some(OutputChange::Lost(name)) => {
+                self.publish_event(SoundscapeEvent::DeviceLost(name));
+            }
+            Some(OutputChange::Restored(mixer)) => {
+                self.move_to_mixer(mixer, false);
+                let name = self.output.info().name.clone();
+                self.publish_event(SoundscapeEvent::DeviceRestored(name));
+            }
+            Some(OutputChange::Switched(mixer)) => self.move_to_mixer(mixer, true),
+            Some(OutputChange::SwitchFailed(e)) => {
+                self.report_error(e.context("Failed to switch output device"));
+            }
+            None => {}
+        }
+
+        // Parse any remote control messages, which may generate CommandQueue items
+        let mut instructions = Vec::new();
+        if let Some(remote_control) = &self.remote_control {
+            while let Some((topic, message)) = self.tether.check_messages() {
+                match remote_control.parse_instructions(&topic, &message) {
+                    Ok(instruction) => instructions.push(instruction),
                     Err(e) => {
                         error!("Failed to parse Remote Instruction: {e}");
                     }
                 }
             }
         }
+        for instruction in instructions {
+            self.receive_instruction(instruction);
+        }
+        for instruction in self.scheduler.take_due() {
+            self.handle_instruction(instruction);
+        }
+
+        if self.recorder.as_ref().is_some_and(Recorder::is_finished) {
+            self.recorder = None;
+        }
+
+        self.update_timetable();
+        self.update_generative_layer();
+        self.update_sequence();
+
         while let Some(command) = self.action_queue.pop() {
             match command {
-                ActionQueueItem::Play(clip_name, volume, fade, should_loop, panning) => {
-                    self.play_one_clip(&clip_name, should_loop, volume, fade, panning);
-                    if let Some(remote) = &self.remote_control {
-                        remote.publish_event(SoundscapeEvent::ClipStarted(clip_name), &self.tether);
-                        self.message_stats.last_events_message = Some(SystemTime::now());
-                    }
+                ActionQueueItem::Play(
+                    clip_name,
+                    volume,
+                    fade,
+                    should_loop,
+                    panning,
+                    quantize,
+                    overrides,
+                ) => {
+                    let delay = self.quantize_delay(quantize);
+                    self.play_one_clip(
+                        &clip_name,
+                        should_loop,
+                        volume,
+                        fade,
+                        panning,
+                        delay,
+                        &overrides,
+                    );
+                    self.publish_event(SoundscapeEvent::ClipStarted(clip_name));
                 }
                 ActionQueueItem::Stop(id, fade) => {
                     if let Some(clip) = self.clips_playing.iter_mut().find(|x| x.id() == id) {
@@ -359,9 +941,14 @@ impl Model {
 
         if let Some(remote) = &mut self.remote_control {
             if remote.state_send_enabled
-                && remote.publish_state_if_ready(&self.tether, &self.clips_playing)
+                && remote.publish_state_if_ready(
+                    &self.tether,
+                    &self.clips_playing,
+                    self.tempo.as_ref(),
+                    self.timetable.active_entry().map(|e| e.label()),
+                )
             {
-                self.message_stats.last_state_message = Some(SystemTime::now());
+                self.message_stats.last_state_message = Some(clock::now());
             }
         }
     }
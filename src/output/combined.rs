@@ -0,0 +1,353 @@
+use std::{collections::VecDeque, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+use log::*;
+use rodio::{cpal::BufferSize, DeviceTrait};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use super::{choose_config, start_stream, DeviceOutput, DeviceSettings, OutputInfo};
+use crate::{
+    devices::select_device,
+    mixer::{Mixer, MixerHandle},
+};
+
+/// Audio queued for every device (on top of any delay compensation), so that the
+/// devices other than the first always have something to play, whenever their
+/// callbacks come
+const CUSHION: Duration = Duration::from_millis(20);
+
+/// How far a device's queue may grow beyond its intended length (i.e. its clock runs
+/// slower than the first device's) before it skips ahead
+const DRIFT_TOLERANCE: Duration = Duration::from_millis(20);
+
+/// Room in each queue beyond its intended length, in case a device stops reading for
+/// a while (e.g. as it is lost)
+const QUEUE_HEADROOM: Duration = Duration::from_secs(1);
+
+/// One of the devices in a combined output, as given to `--output.combine`: chosen in
+/// the same way as `--output.device`, optionally followed by "@" and a delay in
+/// milliseconds, e.g. "focusrite@12"
+#[derive(Debug, Clone, PartialEq)]
+pub struct CombinedDeviceSpec {
+    pub device: String,
+    pub delay: Duration,
+}
+
+impl FromStr for CombinedDeviceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        // Anything after the last "@" that is not a number is part of the device name
+        if let Some((device, delay)) = s.rsplit_once('@') {
+            if let Ok(ms) = delay.trim().parse::<u64>() {
+                return Ok(CombinedDeviceSpec {
+                    device: String::from(device.trim()),
+                    delay: Duration::from_millis(ms),
+                });
+            }
+        }
+        Ok(CombinedDeviceSpec {
+            device: String::from(s.trim()),
+            delay: Duration::ZERO,
+        })
+    }
+}
+
+/// Plays from its own queue, in the stream of one of the devices other than the first.
+/// The queue is a lock-free ring buffer, allocated up front, so nothing here waits or
+/// allocates on the audio thread.
+struct QueueReader {
+    queue: Consumer<f32>,
+    channels: usize,
+    /// Intended length in samples (the cushion plus any delay), on top of the frame
+    /// being played
+    length: usize,
+    /// Longest the queue may grow before skipping ahead
+    max_length: usize,
+    /// Samples still to play of the current frame
+    frame_left: usize,
+    /// Playing silence until the queue is back to its intended length, i.e. at the
+    /// start, or after running dry
+    filling: bool,
+}
+
+impl QueueReader {
+    fn new(queue: Consumer<f32>, channels: usize, length: usize, max_length: usize) -> Self {
+        QueueReader {
+            queue,
+            channels,
+            length,
+            max_length,
+            frame_left: 0,
+            filling: true,
+        }
+    }
+
+    fn start_frame(&mut self) {
+        let queued = self.queue.slots();
+        let target = self.length + self.channels;
+        if self.filling {
+            if queued >= target {
+                self.filling = false;
+                self.skip_to(target);
+            }
+        } else if queued < self.channels {
+            // Ran dry (the device is running fast); rather than carrying on from
+            // whatever arrives next, wait until the queue is full again, so that the
+            // delay is kept
+            self.filling = true;
+        } else if queued > self.max_length {
+            // Running slow
+            self.skip_to(target);
+        }
+    }
+
+    /// Drop the oldest whole frames, down to the given length
+    fn skip_to(&mut self, length: usize) {
+        let excess = self.queue.slots().saturating_sub(length);
+        let skip = excess - excess % self.channels;
+        if let Ok(chunk) = self.queue.read_chunk(skip) {
+            chunk.commit_all();
+        }
+    }
+}
+
+impl Iterator for QueueReader {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_left == 0 {
+            self.start_frame();
+            self.frame_left = self.channels;
+        }
+        self.frame_left -= 1;
+        match self.filling {
+            true => Some(0.),
+            false => Some(self.queue.pop().unwrap_or_default()),
+        }
+    }
+}
+
+/// Runs in the first device's stream: mixes a frame of every channel at a time,
+/// keeping the first device's channels and queueing the rest for the other devices
+struct Splitter<I> {
+    mix: I,
+    /// Channel count of each device, in order
+    channels: Vec<usize>,
+    /// The first device's own samples, starting with silence for the cushion and delay
+    own: VecDeque<f32>,
+    own_length: usize,
+    queues: Vec<Producer<f32>>,
+}
+
+impl<I: Iterator<Item = f32>> Splitter<I> {
+    fn new(mix: I, channels: Vec<usize>, own_length: usize, queues: Vec<Producer<f32>>) -> Self {
+        // Room for one more frame than the delay, so that it never grows
+        let mut own = VecDeque::with_capacity(own_length + channels[0]);
+        own.extend(std::iter::repeat_n(0., own_length));
+        Splitter {
+            mix,
+            channels,
+            own,
+            own_length,
+            queues,
+        }
+    }
+
+    fn mix_frame(&mut self) {
+        for _ in 0..self.channels[0] {
+            self.own.push_back(self.mix.next().unwrap_or_default());
+        }
+        for (queue, channels) in self.queues.iter_mut().zip(&self.channels[1..]) {
+            match queue.write_chunk_uninit(*channels) {
+                Ok(chunk) => {
+                    chunk.fill_from_iter((&mut self.mix).chain(std::iter::repeat(0.)));
+                }
+                // The device has stopped reading for a whole second; its frame is lost
+                Err(_) => self.mix.by_ref().take(*channels).for_each(drop),
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = f32>> Iterator for Splitter<I> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.own.len() <= self.own_length {
+            self.mix_frame();
+        }
+        self.own.pop_front()
+    }
+}
+
+fn samples_in(duration: Duration, sample_rate: u32, channels: u16) -> usize {
+    (duration.as_secs_f64() * sample_rate as f64).round() as usize * channels as usize
+}
+
+/// Open every device given to `--output.combine` at once, as a single range of
+/// channels (in the order given) played from one mixer. The first device sets the pace
+/// for all of them; the others must support the same sample rate.
+pub fn open_combined(
+    settings: &DeviceSettings,
+) -> anyhow::Result<(DeviceOutput, MixerHandle, OutputInfo)> {
+    let mut devices = Vec::new();
+    let mut first_rate = None;
+    for spec in &settings.combine {
+        let device = select_device(std::slice::from_ref(&spec.device))?;
+        let name = device.name().unwrap_or(String::from("unknown"));
+        let device_settings = DeviceSettings {
+            sample_rate: first_rate.or(settings.sample_rate),
+            ..settings.clone()
+        };
+        let (config, sample_format) = choose_config(&device, &device_settings)?;
+        let rate = config.sample_rate.0;
+        match first_rate {
+            Some(first) if first != rate => {
+                return Err(anyhow!(
+                    "\"{}\" cannot play at {} Hz, as the first combined device does",
+                    name,
+                    first
+                ))
+            }
+            _ => first_rate = Some(rate),
+        }
+        devices.push((device, name, config, sample_format, spec.delay));
+    }
+    let sample_rate = first_rate.ok_or(anyhow!("No devices to combine"))?;
+
+    let mut names = Vec::new();
+    let mut first_channel = 0;
+    for (_, name, config, _, delay) in &devices {
+        let last_channel = first_channel + config.channels - 1;
+        let mut description = format!("\"{}\" (ch {}-{}", name, first_channel, last_channel);
+        if !delay.is_zero() {
+            description += &format!(", delay {} ms", delay.as_millis());
+        }
+        description += ")";
+        info!("Combined output: {}", description);
+        names.push(description);
+        first_channel = last_channel + 1;
+    }
+    let total_channels = first_channel;
+
+    let (mixer, mixer_handle) = Mixer::new(total_channels, sample_rate);
+
+    let mut producers = Vec::new();
+    let mut readers = Vec::new();
+    for (_, _, config, _, delay) in &devices[1..] {
+        let length = samples_in(CUSHION + *delay, sample_rate, config.channels);
+        let max_length = length + samples_in(DRIFT_TOLERANCE, sample_rate, config.channels);
+        let capacity = max_length + samples_in(QUEUE_HEADROOM, sample_rate, config.channels);
+        let (producer, consumer) = RingBuffer::new(capacity);
+        producers.push(producer);
+        readers.push(QueueReader::new(
+            consumer,
+            config.channels as usize,
+            length,
+            max_length,
+        ));
+    }
+
+    // The first device starts first; the others play silence until their queues fill
+    let (device, _, config, sample_format, delay) = &devices[0];
+    let own_length = samples_in(CUSHION + *delay, sample_rate, config.channels);
+    let channels = devices
+        .iter()
+        .map(|(_, _, config, _, _)| config.channels as usize)
+        .collect();
+    let splitter = Splitter::new(mixer, channels, own_length, producers);
+    let mut streams = vec![start_stream(device, config, *sample_format, splitter)?];
+    for ((device, _, config, sample_format, _), reader) in devices[1..].iter().zip(readers) {
+        streams.push(start_stream(device, config, *sample_format, reader)?);
+    }
+
+    let info = OutputInfo {
+        name: names.join(" + "),
+        channels: total_channels,
+        sample_rate,
+        buffer_size: match config.buffer_size {
+            BufferSize::Fixed(frames) => Some(frames),
+            BufferSize::Default => None,
+        },
+        sample_format: None,
+    };
+    info!("Output opened with {}", info);
+    Ok((DeviceOutput { streams }, mixer_handle, info))
+}
+
+#[test]
+fn combined_device_specs_parse() {
+    let spec: CombinedDeviceSpec = "focusrite@12".parse().unwrap();
+    assert_eq!(spec.device, "focusrite");
+    assert_eq!(spec.delay, Duration::from_millis(12));
+    let spec: CombinedDeviceSpec = "#2".parse().unwrap();
+    assert_eq!(spec.delay, Duration::ZERO);
+    let spec: CombinedDeviceSpec = "/^USB@Audio/".parse().unwrap();
+    assert_eq!(spec.device, "/^USB@Audio/");
+}
+
+#[test]
+fn splitter_shares_out_channels_with_delay() {
+    // Three channels, numbered by sample: two for the first device, one for the second
+    let mix = (0..).map(|i| i as f32);
+    let (producer, consumer) = RingBuffer::new(100);
+    let mut splitter = Splitter::new(mix, vec![2, 1], 0, vec![producer]);
+    let mut reader = QueueReader::new(consumer, 1, 2, 1000);
+    let mut own = Vec::new();
+    let mut other = Vec::new();
+    for _ in 0..4 {
+        own.extend(splitter.by_ref().take(2));
+        other.extend(reader.by_ref().take(1));
+    }
+    assert_eq!(own, [0., 1., 3., 4., 6., 7., 9., 10.]);
+    // Delayed by two frames of silence
+    assert_eq!(other, [0., 0., 2., 5.]);
+}
+
+#[test]
+fn slow_device_skips_ahead_in_whole_frames() {
+    let (mut producer, consumer) = RingBuffer::new(100);
+    let mut reader = QueueReader::new(consumer, 2, 4, 8);
+    for i in 0..6 {
+        producer.push(i as f32).unwrap();
+    }
+    assert_eq!(reader.by_ref().take(2).collect::<Vec<_>>(), [0., 1.]);
+    for i in 6..20 {
+        producer.push(i as f32).unwrap();
+    }
+    // 18 samples queued; back to the last 6 (4, plus the frame being played)
+    assert_eq!(reader.by_ref().take(2).collect::<Vec<_>>(), [14., 15.]);
+}
+
+#[test]
+fn fast_device_keeps_its_delay() {
+    // One channel each; the first device gets even numbers, the second odd
+    let mix = (0..).map(|i| i as f32);
+    let (producer, consumer) = RingBuffer::new(1000);
+    let mut splitter = Splitter::new(mix, vec![1, 1], 0, vec![producer]);
+    let mut reader = QueueReader::new(consumer, 1, 10, 20);
+    let mut resumes = 0;
+    let mut silent = true;
+    for step in 0..1000 {
+        splitter.next();
+        // The second device plays 10% faster
+        let reads = if step % 10 == 9 { 2 } else { 1 };
+        for sample in reader.by_ref().take(reads) {
+            if sample == 0. {
+                silent = true;
+                continue;
+            }
+            let frame = (sample as usize - 1) / 2;
+            let lag = step - frame;
+            assert!(lag <= 10);
+            if silent {
+                // Back to the full delay each time it has run dry
+                assert_eq!(lag, 10);
+                resumes += 1;
+            }
+            silent = false;
+        }
+    }
+    assert!(resumes > 5);
+}
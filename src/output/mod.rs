@@ -0,0 +1,727 @@
+pub mod combined;
+
+use std::{
+    fmt,
+    fs::File,
+    io::BufWriter,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use hound::{WavSpec, WavWriter};
+use log::*;
+use rodio::{
+    cpal::{
+        traits::StreamTrait, BufferSize, FromSample, SampleFormat, SampleRate, SizedSample, Stream,
+        StreamConfig, SupportedBufferSize,
+    },
+    DeviceTrait,
+};
+
+use crate::{
+    devices::select_device,
+    mixer::{Mixer, MixerHandle},
+    output::combined::CombinedDeviceSpec,
+    settings::Cli,
+    utils::sample_to_i16,
+};
+
+/// How often a virtual output pulls from the mixer
+const VIRTUAL_BLOCK: Duration = Duration::from_millis(10);
+
+/// How often a file output updates the WAV header, so that the file stays valid even
+/// if the process is killed
+const FILE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sample rate for null or file output, if not specified
+const DEFAULT_VIRTUAL_SAMPLE_RATE: u32 = 48000;
+
+/// A sound card stream that has not asked for any audio for this long is treated as
+/// lost, since some platforms stop calling back without reporting an error
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often to try reopening a sound card that has been lost
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Fade for clips moving from one output to another; the old output is kept playing
+/// for a little longer than this, so that they fade out of it as they fade in on the new
+pub const HANDOVER_FADE: Duration = Duration::from_millis(100);
+
+/// Sample formats that can be requested for a sound card
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum OutputSampleFormat {
+    I16,
+    U16,
+    I32,
+    F32,
+}
+
+impl From<OutputSampleFormat> for SampleFormat {
+    fn from(format: OutputSampleFormat) -> Self {
+        match format {
+            OutputSampleFormat::I16 => SampleFormat::I16,
+            OutputSampleFormat::U16 => SampleFormat::U16,
+            OutputSampleFormat::I32 => SampleFormat::I32,
+            OutputSampleFormat::F32 => SampleFormat::F32,
+        }
+    }
+}
+
+/// Where the mix is played; must be kept alive for as long as playback should continue
+pub enum Output {
+    Device(DeviceOutput),
+    /// Consumes the mix in real time without any hardware, optionally writing it to a
+    /// file
+    Virtual {
+        _thread: thread::JoinHandle<()>,
+        running: Arc<AtomicBool>,
+    },
+    /// A sound card opened by an [Opening] thread, which keeps its streams (which
+    /// cannot be moved between threads) playing until this is dropped
+    Background {
+        monitors: Vec<StreamMonitor>,
+        _keep: Sender<()>,
+    },
+}
+
+impl Output {
+    /// True if a sound card output has been lost
+    fn is_lost(&mut self) -> bool {
+        match self {
+            Output::Device(device) => device.is_lost(),
+            Output::Background { monitors, .. } => monitors.iter_mut().any(StreamMonitor::is_lost),
+            Output::Virtual { .. } => false,
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        // A virtual output has no stream to drop, so its thread is told to finish
+        if let Output::Virtual { running, .. } = self {
+            running.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Shared with the stream callbacks, to notice when a sound card has gone away
+#[derive(Default)]
+struct StreamHealth {
+    failed: AtomicBool,
+    callbacks: AtomicU64,
+}
+
+/// Watches a playing stream for errors or stalling
+pub struct StreamMonitor {
+    health: Arc<StreamHealth>,
+    last_callbacks: u64,
+    last_progress: Instant,
+}
+
+/// A playing stream, with its monitor
+struct MonitoredStream {
+    stream: Stream,
+    monitor: StreamMonitor,
+}
+
+impl StreamMonitor {
+    /// True once the stream has reported an error, or stopped asking for audio
+    fn is_lost(&mut self) -> bool {
+        if self.health.failed.load(Ordering::Relaxed) {
+            return true;
+        }
+        let callbacks = self.health.callbacks.load(Ordering::Relaxed);
+        if callbacks != self.last_callbacks {
+            self.last_callbacks = callbacks;
+            self.last_progress = Instant::now();
+        }
+        self.last_progress.elapsed() > STALL_TIMEOUT
+    }
+}
+
+/// One or more sound cards (see [combined]), all playing the same mix
+pub struct DeviceOutput {
+    streams: Vec<MonitoredStream>,
+}
+
+impl DeviceOutput {
+    /// True if any of the streams has been lost
+    fn is_lost(&mut self) -> bool {
+        self.streams.iter_mut().any(|s| s.monitor.is_lost())
+    }
+
+    /// Separate the streams, which must stay on the thread that opened them, from
+    /// their monitors, which need not
+    fn into_parts(self) -> (Vec<Stream>, Vec<StreamMonitor>) {
+        self.streams
+            .into_iter()
+            .map(|s| (s.stream, s.monitor))
+            .unzip()
+    }
+}
+
+/// Options for opening a sound card, from the command line
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSettings {
+    pub devices: Vec<String>,
+    /// Devices to play all at once, as one combined range of channels; if given,
+    /// `devices` is not used
+    pub combine: Vec<CombinedDeviceSpec>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub sample_format: Option<OutputSampleFormat>,
+}
+
+impl From<&Cli> for DeviceSettings {
+    fn from(cli: &Cli) -> Self {
+        DeviceSettings {
+            devices: cli.preferred_output_devices.clone(),
+            combine: cli.combined_output_devices.clone(),
+            sample_rate: cli.output_sample_rate,
+            buffer_size: cli.output_buffer_size,
+            sample_format: cli.output_sample_format,
+        }
+    }
+}
+
+/// A change in the output that the Model needs to act on
+pub enum OutputChange {
+    /// The sound card has gone; playback is silent until it (or a fallback) is back
+    Lost(String),
+    /// A sound card has been reopened, with a new mixer for playback to move to
+    Restored(MixerHandle),
+    /// Switched to another sound card, with a new mixer for playback to move to; the
+    /// old output keeps playing for long enough to fade out
+    Switched(MixerHandle),
+    /// The sound card to switch to could not be opened; the current output carries on
+    SwitchFailed(anyhow::Error),
+}
+
+/// A sound card opened in the background, for the Model to move playback to
+struct Opened {
+    monitors: Vec<StreamMonitor>,
+    keep: Sender<()>,
+    mixer_handle: MixerHandle,
+    info: OutputInfo,
+}
+
+impl Opened {
+    fn into_parts(self) -> (Output, MixerHandle, OutputInfo) {
+        let output = Output::Background {
+            monitors: self.monitors,
+            _keep: self.keep,
+        };
+        (output, self.mixer_handle, self.info)
+    }
+}
+
+/// Opens a sound card on another thread, so that the Model (and the GUI) is never
+/// held up by a slow or unresponsive device. Dropping this stops any further attempts.
+struct Opening {
+    opened: Receiver<anyhow::Result<Opened>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Opening {
+    /// Keep trying to reopen a lost sound card, until it (or a fallback) opens
+    fn reconnect(settings: DeviceSettings) -> Self {
+        Self::start(settings, true)
+    }
+
+    /// Try once, straight away, e.g. to switch to another sound card
+    fn once(settings: DeviceSettings) -> Self {
+        Self::start(settings, false)
+    }
+
+    fn start(settings: DeviceSettings, retry: bool) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let thread_cancelled = cancelled.clone();
+        thread::spawn(move || {
+            while !thread_cancelled.load(Ordering::Relaxed) {
+                if retry {
+                    thread::sleep(RECONNECT_INTERVAL);
+                }
+                match open(&settings) {
+                    Ok((device, mixer_handle, info)) => {
+                        let (streams, monitors) = device.into_parts();
+                        let (keep, until_dropped) = mpsc::channel();
+                        let opened = Opened {
+                            monitors,
+                            keep,
+                            mixer_handle,
+                            info,
+                        };
+                        if tx.send(Ok(opened)).is_ok() {
+                            // Nothing is ever sent; this returns once the output is dropped
+                            let _ = until_dropped.recv();
+                        }
+                        drop(streams);
+                        return;
+                    }
+                    Err(e) if retry => debug!("Output device still unavailable: {:#}", e),
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                }
+            }
+        });
+        Opening {
+            opened: rx,
+            cancelled,
+        }
+    }
+}
+
+impl Drop for Opening {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The previous output, playing out the handover fade after a switch. Its stream is
+/// dropped before the handle to its mixer (fields drop in order), so that any voices
+/// it finishes with are still handed back rather than freed on the audio thread.
+struct Retiring {
+    _output: Output,
+    mixer: Option<MixerHandle>,
+    since: Instant,
+}
+
+/// The output in use, which reopens the sound card (or one of the fallbacks) whenever
+/// it is lost, e.g. unplugged
+pub struct AudioOutput {
+    /// None while the sound card is lost, or when rendering offline
+    output: Option<Output>,
+    /// The mixer playing through `output`, kept to drain it once it is retiring
+    mixer: Option<MixerHandle>,
+    /// For opening (or reopening) a sound card
+    settings: DeviceSettings,
+    info: OutputInfo,
+    /// Reopening the sound card in the background, while lost
+    reconnect: Option<Opening>,
+    /// Opening another sound card in the background, with the settings to keep once
+    /// it has opened
+    switching: Option<(Opening, DeviceSettings)>,
+    retiring: Option<Retiring>,
+}
+
+impl AudioOutput {
+    /// No output at all: the Model's mixer is pulled directly (i.e. offline render)
+    pub fn offline(info: OutputInfo) -> Self {
+        AudioOutput {
+            output: None,
+            mixer: None,
+            settings: DeviceSettings::default(),
+            info,
+            reconnect: None,
+            switching: None,
+            retiring: None,
+        }
+    }
+
+    pub fn info(&self) -> &OutputInfo {
+        &self.info
+    }
+
+    /// True while waiting for a lost sound card to come back
+    pub fn is_lost(&self) -> bool {
+        self.reconnect.is_some()
+    }
+
+    /// Start switching to another sound card (chosen in the same way as
+    /// `--output.device`), keeping the other output settings. The device is opened in
+    /// the background; [Self::check] reports when playback can move to it.
+    pub fn switch_to(&mut self, device: &str) -> anyhow::Result<()> {
+        if self.output.is_none() && !self.is_lost() {
+            return Err(anyhow!("The output cannot be changed in an offline render"));
+        }
+        let settings = DeviceSettings {
+            devices: vec![String::from(device)],
+            combine: Vec::new(),
+            ..self.settings.clone()
+        };
+        // Replaces (and so cancels) any switch still in progress
+        self.switching = Some((Opening::once(settings.clone()), settings));
+        Ok(())
+    }
+
+    /// Check on the sound card (if any), and try to reopen it if it has been lost
+    pub fn check(&mut self) -> Option<OutputChange> {
+        self.check_retiring();
+        if let Some(change) = self.check_switch() {
+            return Some(change);
+        }
+        if let Some(reconnect) = &self.reconnect {
+            let Ok(Ok(reopened)) = reconnect.opened.try_recv() else {
+                return None;
+            };
+            info!("Output restored: {}", reopened.info);
+            let (output, mixer, info) = reopened.into_parts();
+            self.output = Some(output);
+            self.mixer = Some(mixer.clone());
+            self.info = info;
+            self.reconnect = None;
+            return Some(OutputChange::Restored(mixer));
+        }
+        if !self.output.as_mut().is_some_and(Output::is_lost) {
+            return None;
+        }
+        error!(
+            "Output device \"{}\" lost; will keep trying to reopen it",
+            self.info.name
+        );
+        // Dropping the stream also drops the mixer, and every voice with it
+        self.output = None;
+        self.mixer = None;
+        self.reconnect = Some(Opening::reconnect(self.settings.clone()));
+        Some(OutputChange::Lost(self.info.name.clone()))
+    }
+
+    /// Drop the voices the old mixer has finished with, and the old output itself
+    /// once the handover fade is over
+    fn check_retiring(&mut self) {
+        let Some(retiring) = &self.retiring else {
+            return;
+        };
+        if let Some(mixer) = &retiring.mixer {
+            mixer.drop_retired();
+        }
+        if retiring.since.elapsed() > HANDOVER_FADE * 2 {
+            self.retiring = None;
+        }
+    }
+
+    /// Take over a sound card opened for a switch, once it is ready
+    fn check_switch(&mut self) -> Option<OutputChange> {
+        let (switch, _) = self.switching.as_ref()?;
+        let opened = match switch.opened.try_recv() {
+            Ok(opened) => opened,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("Output device could not be opened")),
+        };
+        let (_, settings) = self.switching.take()?;
+        let opened = match opened {
+            Ok(opened) => opened,
+            Err(e) => return Some(OutputChange::SwitchFailed(e)),
+        };
+        info!("Output switched to {}", opened.info);
+        let (output, mixer, info) = opened.into_parts();
+        let previous_mixer = self.mixer.replace(mixer.clone());
+        if let Some(previous) = self.output.replace(output) {
+            self.retiring = Some(Retiring {
+                _output: previous,
+                mixer: previous_mixer,
+                since: Instant::now(),
+            });
+        }
+        // From now on, this is the device to reopen if it is lost
+        self.settings = settings;
+        self.info = info;
+        self.reconnect = None;
+        Some(OutputChange::Switched(mixer))
+    }
+}
+
+/// The output configuration actually in use, for display
+#[derive(Debug, Clone)]
+pub struct OutputInfo {
+    pub name: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// In frames, if fixed
+    pub buffer_size: Option<u32>,
+    pub sample_format: Option<SampleFormat>,
+}
+
+impl fmt::Display for OutputInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\": x{} channels, {} Hz",
+            self.name, self.channels, self.sample_rate
+        )?;
+        match self.buffer_size {
+            Some(frames) => write!(f, ", buffer {} frames", frames)?,
+            None => write!(f, ", default buffer")?,
+        }
+        if let Some(format) = self.sample_format {
+            write!(f, ", {}", format)?;
+        }
+        Ok(())
+    }
+}
+
+/// Open the output chosen on the command line (a sound card, unless a null or file
+/// output was requested), with the mixer already playing
+pub fn open_output(cli: &Cli) -> (AudioOutput, MixerHandle) {
+    if cli.output_null || cli.output_file.is_some() {
+        let channels = cli.output_channels.unwrap_or(2);
+        let sample_rate = cli
+            .output_sample_rate
+            .unwrap_or(DEFAULT_VIRTUAL_SAMPLE_RATE);
+        let (mixer, mixer_handle) = Mixer::new(channels, sample_rate);
+        let writer = cli.output_file.as_ref().map(|path| {
+            info!("Output to file \"{}\" (in real time)", path);
+            let spec = WavSpec {
+                channels,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            WavWriter::create(path, spec).expect("failed to create output file")
+        });
+        if writer.is_none() {
+            info!("Null output; audio is discarded");
+        }
+        let info = OutputInfo {
+            name: match &cli.output_file {
+                Some(path) => format!("file {}", path),
+                None => String::from("null"),
+            },
+            channels,
+            sample_rate,
+            buffer_size: None,
+            sample_format: None,
+        };
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || run_virtual(mixer, writer, thread_running));
+        let output = AudioOutput {
+            output: Some(Output::Virtual {
+                _thread: thread,
+                running,
+            }),
+            mixer: Some(mixer_handle.clone()),
+            settings: DeviceSettings::from(cli),
+            info,
+            reconnect: None,
+            switching: None,
+            retiring: None,
+        };
+        (output, mixer_handle)
+    } else {
+        let settings = DeviceSettings::from(cli);
+        let (device, mixer_handle, info) =
+            open(&settings).unwrap_or_else(|e| panic!("failed to open output device: {e:#}"));
+        let output = AudioOutput {
+            output: Some(Output::Device(device)),
+            mixer: Some(mixer_handle.clone()),
+            settings,
+            info,
+            reconnect: None,
+            switching: None,
+            retiring: None,
+        };
+        (output, mixer_handle)
+    }
+}
+
+/// Open the sound card (or combination of sound cards) as set
+fn open(settings: &DeviceSettings) -> anyhow::Result<(DeviceOutput, MixerHandle, OutputInfo)> {
+    if settings.combine.is_empty() {
+        open_device(settings)
+    } else {
+        combined::open_combined(settings)
+    }
+}
+
+fn open_device(
+    settings: &DeviceSettings,
+) -> anyhow::Result<(DeviceOutput, MixerHandle, OutputInfo)> {
+    let device = select_device(&settings.devices)?;
+    let name = device.name().unwrap_or(String::from("unknown"));
+    info!("Device was set; name \"{}\"", name);
+
+    let (config, sample_format) = choose_config(&device, settings)?;
+
+    // Every clip is played through this single mixer
+    let (mixer, mixer_handle) = Mixer::new(config.channels, config.sample_rate.0);
+    let stream = start_stream(&device, &config, sample_format, mixer)?;
+
+    let info = OutputInfo {
+        name,
+        channels: config.channels,
+        sample_rate: config.sample_rate.0,
+        buffer_size: match config.buffer_size {
+            BufferSize::Fixed(frames) => Some(frames),
+            BufferSize::Default => None,
+        },
+        sample_format: Some(sample_format),
+    };
+    info!("Output opened with {}", info);
+    let device = DeviceOutput {
+        streams: vec![stream],
+    };
+    Ok((device, mixer_handle, info))
+}
+
+/// Apply any requested sample rate, buffer size and sample format to the device's
+/// default configuration (keeping its channel count), falling back to the default
+/// where the device cannot support them
+fn choose_config(
+    device: &rodio::Device,
+    settings: &DeviceSettings,
+) -> anyhow::Result<(StreamConfig, SampleFormat)> {
+    let default = device.default_output_config()?;
+    let channels = default.channels();
+    let sample_format = settings
+        .sample_format
+        .map(SampleFormat::from)
+        .unwrap_or(default.sample_format());
+    let sample_rate = settings
+        .sample_rate
+        .map(SampleRate)
+        .unwrap_or(default.sample_rate());
+
+    let supported = device
+        .supported_output_configs()?
+        .find(|c| {
+            c.channels() == channels
+                && c.sample_format() == sample_format
+                && c.min_sample_rate() <= sample_rate
+                && sample_rate <= c.max_sample_rate()
+        })
+        .map(|c| c.with_sample_rate(sample_rate))
+        .unwrap_or_else(|| {
+            warn!(
+                "Device does not support {} at {} Hz with x{} channels; using its default instead",
+                sample_format, sample_rate.0, channels
+            );
+            default
+        });
+
+    let buffer_size = match (settings.buffer_size, supported.buffer_size()) {
+        (None, _) => BufferSize::Default,
+        (Some(frames), SupportedBufferSize::Range { min, max }) => {
+            let clamped = frames.clamp(*min, *max);
+            if clamped != frames {
+                warn!(
+                    "Buffer size {} is outside the range supported by the device ({}-{}); using {}",
+                    frames, min, max, clamped
+                );
+            }
+            BufferSize::Fixed(clamped)
+        }
+        (Some(frames), SupportedBufferSize::Unknown) => BufferSize::Fixed(frames),
+    };
+
+    let mut config = supported.config();
+    config.buffer_size = buffer_size;
+    Ok((config, supported.sample_format()))
+}
+
+/// Start playing the source (e.g. the mixer) on the device, in whichever sample format
+/// was chosen
+fn start_stream<S>(
+    device: &rodio::Device,
+    config: &StreamConfig,
+    sample_format: SampleFormat,
+    source: S,
+) -> anyhow::Result<MonitoredStream>
+where
+    S: Iterator<Item = f32> + Send + 'static,
+{
+    let health = Arc::new(StreamHealth::default());
+    let h = health.clone();
+    let stream = match sample_format {
+        SampleFormat::I16 => build_stream::<i16, _>(device, config, source, h),
+        SampleFormat::U16 => build_stream::<u16, _>(device, config, source, h),
+        SampleFormat::I32 => build_stream::<i32, _>(device, config, source, h),
+        SampleFormat::F32 => build_stream::<f32, _>(device, config, source, h),
+        SampleFormat::F64 => build_stream::<f64, _>(device, config, source, h),
+        other => return Err(anyhow!("Unsupported sample format {}", other)),
+    }?;
+    stream.play()?;
+    Ok(MonitoredStream {
+        stream,
+        monitor: StreamMonitor {
+            health,
+            last_callbacks: 0,
+            last_progress: Instant::now(),
+        },
+    })
+}
+
+fn build_stream<T, S>(
+    device: &rodio::Device,
+    config: &StreamConfig,
+    mut source: S,
+    health: Arc<StreamHealth>,
+) -> anyhow::Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+    S: Iterator<Item = f32> + Send + 'static,
+{
+    let callback_health = health.clone();
+    let stream = device.build_output_stream::<T, _, _>(
+        config,
+        move |data: &mut [T], _| {
+            callback_health.callbacks.fetch_add(1, Ordering::Relaxed);
+            for sample in data.iter_mut() {
+                *sample = T::from_sample(source.next().unwrap_or_default());
+            }
+        },
+        move |e| {
+            error!("Output stream error: {}", e);
+            health.failed.store(true, Ordering::Relaxed);
+        },
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Pull from the mixer at the same pace as a sound card would, until told to stop
+fn run_virtual(
+    mut mixer: Mixer,
+    mut writer: Option<WavWriter<BufWriter<File>>>,
+    running: Arc<AtomicBool>,
+) {
+    let sample_rate = rodio::Source::sample_rate(&mixer) as f64;
+    let channels = rodio::Source::channels(&mixer) as usize;
+    let block_frames = (VIRTUAL_BLOCK.as_secs_f64() * sample_rate).round() as u64;
+    let start = Instant::now();
+    let mut last_flush = start;
+    let mut frames: u64 = 0;
+
+    while running.load(Ordering::Relaxed) {
+        for sample in (&mut mixer).take(block_frames as usize * channels) {
+            if let Some(w) = &mut writer {
+                if let Err(e) = w.write_sample(sample_to_i16(sample)) {
+                    error!(
+                        "Failed to write to output file: {}; audio is now discarded",
+                        e
+                    );
+                    writer = None;
+                }
+            }
+        }
+        frames += block_frames;
+
+        if last_flush.elapsed() >= FILE_FLUSH_INTERVAL {
+            if let Some(w) = &mut writer {
+                if let Err(e) = w.flush() {
+                    warn!("Failed to update output file: {}", e);
+                }
+            }
+            last_flush = Instant::now();
+        }
+
+        let due = start + Duration::from_secs_f64(frames as f64 / sample_rate);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+    if let Some(w) = writer {
+        if let Err(e) = w.finalize() {
+            warn!("Failed to complete output file: {}", e);
+        }
+    }
+}
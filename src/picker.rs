@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    SeedableRng,
+};
+
+/// How many previous picks are remembered, i.e. the maximum useful "avoid last N" value
+const MAX_HISTORY: usize = 64;
+
+/// Options for random picks, as specified in a Scene Message
+#[derive(Debug, Default, Clone)]
+pub struct RandomPickOptions {
+    /// Relative weights, in the same order as the clip names; if omitted, the
+    /// weights from the Sample Bank (or equal weights) are used
+    pub weights: Option<Vec<f32>>,
+    /// Avoid any of the last N clips picked, if possible
+    pub avoid_repeat: Option<usize>,
+}
+
+/// Source of all randomness for picking clips; can be seeded for repeatable behaviour
+pub struct ClipPicker {
+    rng: StdRng,
+    history: VecDeque<String>,
+}
+
+impl ClipPicker {
+    pub fn new(seed: Option<u64>) -> Self {
+        ClipPicker {
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// Pick one of the candidates, with the given relative weights (same length as
+    /// the candidates), and avoiding any of the last `avoid_last` picks if possible.
+    pub fn pick(
+        &mut self,
+        candidates: &[String],
+        weights: &[f32],
+        avoid_last: usize,
+    ) -> anyhow::Result<String> {
+        if candidates.is_empty() {
+            return Err(anyhow!("Cannot pick from an empty list of clips"));
+        }
+        if candidates.len() != weights.len() {
+            return Err(anyhow!(
+                "Got {} weights for {} clips",
+                weights.len(),
+                candidates.len()
+            ));
+        }
+
+        let recent: Vec<&String> = self.history.iter().rev().take(avoid_last).collect();
+        let is_allowed = |name: &String| !recent.iter().any(|r| r.eq_ignore_ascii_case(name));
+
+        // If every candidate was played recently (or has zero weight), rather repeat than
+        // play nothing at all
+        let filtered_weights: Vec<f32> = candidates
+            .iter()
+            .zip(weights)
+            .map(|(name, w)| if is_allowed(name) { w.max(0.) } else { 0. })
+            .collect();
+        let index = match WeightedIndex::new(&filtered_weights) {
+            Ok(distribution) => distribution.sample(&mut self.rng),
+            Err(_) => WeightedIndex::new(weights.iter().map(|w| w.max(0.)))
+                .map_err(|e| anyhow!("Invalid clip weights: {e}"))?
+                .sample(&mut self.rng),
+        };
+
+        let picked = candidates[index].clone();
+        self.history.push_back(picked.clone());
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        Ok(picked)
+    }
+}
+
+#[cfg(test)]
+fn names(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| String::from(*s)).collect()
+}
+
+#[test]
+fn empty_list_is_an_error() {
+    let mut picker = ClipPicker::new(Some(1));
+    assert!(picker.pick(&[], &[], 0).is_err());
+}
+
+#[test]
+fn same_seed_same_picks() {
+    let candidates = names(&["a", "b", "c", "d"]);
+    let weights = [1.0; 4];
+    let mut first = ClipPicker::new(Some(42));
+    let mut second = ClipPicker::new(Some(42));
+    for _ in 0..20 {
+        assert_eq!(
+            first.pick(&candidates, &weights, 0).unwrap(),
+            second.pick(&candidates, &weights, 0).unwrap()
+        );
+    }
+}
+
+#[test]
+fn zero_weight_never_picked() {
+    let candidates = names(&["a", "b"]);
+    let mut picker = ClipPicker::new(Some(7));
+    for _ in 0..50 {
+        assert_eq!(picker.pick(&candidates, &[0., 1.], 0).unwrap(), "b");
+    }
+}
+
+#[test]
+fn avoids_recent_picks() {
+    let candidates = names(&["a", "b", "c"]);
+    let weights = [1.0; 3];
+    let mut picker = ClipPicker::new(Some(3));
+    let mut previous: Vec<String> = Vec::new();
+    for _ in 0..30 {
+        let pick = picker.pick(&candidates, &weights, 2).unwrap();
+        assert!(!previous.iter().rev().take(2).any(|p| p == &pick));
+        previous.push(pick);
+    }
+}
+
+#[test]
+fn repeats_rather_than_fail() {
+    let candidates = names(&["a"]);
+    let mut picker = ClipPicker::new(None);
+    picker.pick(&candidates, &[1.], 1).unwrap();
+    assert_eq!(picker.pick(&candidates, &[1.], 1).unwrap(), "a");
+}
@@ -1,14 +1,33 @@
 use std::{
     fs::File,
     io::BufReader,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
 use log::debug;
-use rodio::{source::ChannelVolume, Decoder, OutputStreamHandle, Sink, Source};
+use rand::Rng;
+use rodio::{
+    source::{ChannelVolume, UniformSourceIterator},
+    Decoder, Source,
+};
 use tween::{Linear, Tween, Tweener};
 
-use crate::{loader::AudioClipOnDisk, panning::simple_panning_channel_volumes};
+use crate::{
+    cache::ClipCache,
+    clock,
+    dsp::{
+        filter::{Filter, FilterControl, FilterUpdate},
+        speed::VariableSpeed,
+        SharedF32,
+    },
+    loader::AudioClipOnDisk,
+    mixer::{MixerCommand, MixerHandle, Voice, VoiceStatus},
+    panning::simple_panning_channel_volumes,
+    region::{Loops, PlaybackRegion},
+    utils::{semitones_to_rate, MAX_RATE, MIN_RATE},
+    variants::random_in_range,
+};
 
 // use crate::utils::millis_to_frames;
 
@@ -18,41 +37,93 @@ type StoredTweener = Tweener<f32, u128, Box<dyn Tween<f32> + Send + Sync>>;
 /// Position (in range 0>numChannels-1) and spread (in range 1>numChannels)
 pub type PanWithRange = (f32, f32);
 
+/// Per-playback settings (e.g. from a clipCommands message), which override those
+/// in the Sample Bank if provided
+#[derive(Debug, Default, Clone)]
+pub struct PlaybackOverrides {
+    /// Milliseconds from the start of the file to begin playback
+    pub start_offset: Option<u64>,
+    pub loop_start: Option<u64>,
+    pub loop_end: Option<u64>,
+    /// Number of times to play the loop region, before continuing to the end of the file
+    pub loop_count: Option<u32>,
+    /// Playback rate multiplier (1.0 is normal speed)
+    pub rate: Option<f32>,
+    pub filter: FilterUpdate,
+    /// Level sent to the shared reverb, from 0 (dry only) to 1
+    pub reverb_send: Option<f32>,
+}
+
 pub enum PlaybackPhase {
     Attack(StoredTweener),
     Sustain(),
     Release(SystemTime, StoredTweener),
 }
 
-pub struct ClipWithSink {
+pub struct PlayingClip {
     id: usize,
-    sink: Sink,
+    mixer: MixerHandle,
+    status: Arc<VoiceStatus>,
+    /// The variant picked for this playback
+    path: String,
+    preload: bool,
+    panning: Option<PanWithRange>,
+    reverb_send: f32,
+    paused: bool,
+    /// Gain most recently sent to the mixer
+    last_gain_sent: Option<f32>,
+    /// Duration of the file itself, if known
     duration: Option<Duration>,
-    started: SystemTime,
-    time_last_paused: Option<SystemTime>,
-    elapsed_to_remove: Option<Duration>,
+    /// When the first voice was due to start, i.e. after any start delay
+    start_due: SystemTime,
+    region: PlaybackRegion,
+    /// Playback rate multiplier, as requested
+    rate: f32,
+    /// Extra rate multiplier from a random pitch shift, fixed for this playback
+    rate_variation: f32,
+    rate_ramp: Option<(SystemTime, StoredTweener)>,
+    rate_control: Arc<SharedF32>,
+    filter: Arc<FilterControl>,
+    /// Start time, cutoff (as log2 of the frequency) and resonance
+    filter_ramp: Option<(SystemTime, StoredTweener, StoredTweener)>,
+    /// How far into the source playback has got, i.e. time played scaled by the rate
+    source_elapsed: Duration,
+    /// Time played at the last progress update
+    last_elapsed: Duration,
     last_known_progress: Option<f32>,
     is_looping: bool,
     name: String,
+    /// Fades are applied sample by sample in the mixer; the phase and envelope here
+    /// follow along only for reporting
     current_phase: PlaybackPhase,
-    current_volume: f32,
+    /// Estimated fade level, from 0 to 1
+    envelope: f32,
+    /// Clip volume, before any fade
+    volume: f32,
+    tags: Vec<String>,
+    /// Multiplier applied on top of the current volume, e.g. by ducking rules
+    ducking_gain: f32,
 }
 
-impl ClipWithSink {
+impl PlayingClip {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: usize,
-        sample: &AudioClipOnDisk,
+        sample: &mut AudioClipOnDisk,
         should_loop: bool,
         override_volume: Option<f32>,
         fade_in: Option<Duration>,
         override_panning: Option<PanWithRange>,
-        output_stream_handle: &OutputStreamHandle,
+        start_delay: Option<Duration>,
+        overrides: &PlaybackOverrides,
+        ducking_gain: f32,
+        mixer: &MixerHandle,
         output_channels: u16,
+        cache: &mut ClipCache,
+        rng: &mut impl Rng,
     ) -> Self {
-        let sink = Sink::try_new(output_stream_handle).expect("failed to create sink");
-        debug!("Attempt to play {}", sample.path());
-        let file = BufReader::new(File::open(sample.path()).unwrap());
+        let path = String::from(sample.pick_variant(rng));
+        debug!("Attempt to play {}", path);
         // let source = Decoder::new(file).unwrap();
         // let duration = source.total_duration();
         // let mut duration = None;
@@ -63,10 +134,104 @@ impl ClipWithSink {
             sample.panning()
         };
 
-        let decoder = Decoder::new(file).unwrap();
+        let region = PlaybackRegion::new(
+            overrides.start_offset.or(sample.start_offset()),
+            overrides.loop_start.or(sample.loop_start()),
+            overrides.loop_end.or(sample.loop_end()),
+            overrides.loop_count.or(sample.loop_count()),
+            sample.loop_crossfade(),
+            should_loop,
+        );
+
+        let rate = overrides.rate.or(sample.rate()).unwrap_or(1.0);
+        let rate_variation = random_in_range(sample.pitch_range(), rng)
+            .map(semitones_to_rate)
+            .unwrap_or(1.0);
+        let rate_control = Arc::new(SharedF32::new(
+            (rate * rate_variation).clamp(MIN_RATE, MAX_RATE),
+        ));
+
+        let filter = FilterControl::new(sample.filter());
+        apply_filter_update(&filter, &overrides.filter);
+
+        let volume = parse_optional_volume(sample.volume(), override_volume)
+            * random_in_range(sample.volume_range(), rng).unwrap_or(1.0);
+        let fade_in = fade_in.unwrap_or(Duration::from_millis(8));
+        let reverb_send = overrides.reverb_send.or(sample.reverb_send()).unwrap_or(0.);
+
+        let tween: Box<dyn Tween<f32> + Send + Sync> = Box::new(Linear);
+        let stored_tweener = Tweener::new(0., 1., fade_in.as_millis(), tween);
+
+        let is_looping = region.loops == Loops::Infinite;
+
+        let mut clip = PlayingClip {
+            id,
+            mixer: mixer.clone(),
+            status: Arc::default(),
+            path,
+            preload: sample.preload(),
+            panning,
+            reverb_send,
+            paused: false,
+            last_gain_sent: Some(volume * ducking_gain),
+            duration: None,
+            start_due: clock::now() + start_delay.unwrap_or_default(),
+            region,
+            rate,
+            rate_variation,
+            rate_ramp: None,
+            rate_control,
+            filter,
+            filter_ramp: None,
+            source_elapsed: Duration::ZERO,
+            last_elapsed: Duration::ZERO,
+            last_known_progress: Some(0.),
+            name: String::from(sample.name()),
+            current_phase: PlaybackPhase::Attack(stored_tweener),
+            envelope: 0.,
+            volume,
+            is_looping,
+            tags: sample.tags().to_vec(),
+            ducking_gain,
+        };
+        clip.start_voice(
+            cache,
+            output_channels,
+            start_delay.unwrap_or_default(),
+            fade_in,
+            volume * ducking_gain,
+        );
+        clip
+    }
+
+    /// Build the source chain and hand it to the mixer as a new voice, continuing from
+    /// however far the source has already got (i.e. from the start, for a new clip)
+    fn start_voice(
+        &mut self,
+        cache: &mut ClipCache,
+        output_channels: u16,
+        start_delay: Duration,
+        fade_in: Duration,
+        gain: f32,
+    ) {
+        let decoder: Box<dyn Source<Item = i16> + Send> =
+            match cache.source(&self.path, self.preload) {
+                Some(cached) => Box::new(cached),
+                None => {
+                    let file = BufReader::new(File::open(&self.path).unwrap());
+                    Box::new(Decoder::new(file).unwrap())
+                }
+            };
+        self.duration = decoder.total_duration();
+
+        let region_source = self.region.build_source(decoder, self.source_elapsed);
+        let decoder = VariableSpeed::new(
+            Filter::new(region_source, self.filter.clone()),
+            self.rate_control.clone(),
+        );
 
         let source: Box<dyn Source<Item = _> + Send> = {
-            if let Some((position, spread)) = panning {
+            if let Some((position, spread)) = self.panning {
                 let s = ChannelVolume::new(
                     decoder,
                     simple_panning_channel_volumes(position, spread, output_channels),
@@ -77,76 +242,140 @@ impl ClipWithSink {
             }
         };
 
-        // if let Some(src) = source {
-        let duration = source.total_duration();
-        if should_loop {
-            sink.append(source.repeat_infinite());
-        } else {
-            sink.append(source);
-        }
-        // }
+        // The mixer expects every voice in the output format already
+        let source = UniformSourceIterator::<_, f32>::new(
+            source,
+            self.mixer.channels(),
+            self.mixer.sample_rate(),
+        );
 
-        let tween: Box<dyn Tween<f32> + Send + Sync> = Box::new(Linear);
-        let stored_tweener = Tweener::new(
-            0.,
-            parse_optional_volume(sample.volume(), override_volume),
-            fade_in.unwrap_or(Duration::from_millis(8)).as_millis(),
-            tween,
+        // A start delay (e.g. for quantisation) is counted out by the mixer itself
+        let (voice, status) = Voice::new(
+            self.id,
+            Box::new(source),
+            start_delay,
+            gain,
+            fade_in,
+            self.reverb_send,
         );
+        self.mixer.send(MixerCommand::Play(Box::new(voice)));
+        self.status = status;
+        self.last_elapsed = Duration::ZERO;
+    }
 
-        ClipWithSink {
-            id,
-            sink,
-            duration,
-            started: SystemTime::now(),
-            time_last_paused: None,
-            elapsed_to_remove: None,
-            last_known_progress: Some(0.),
-            name: String::from(sample.name()),
-            current_phase: PlaybackPhase::Attack(stored_tweener),
-            current_volume: 0.,
-            is_looping: should_loop,
+    /// Carry on playing through another mixer (e.g. after the output device has
+    /// changed), from the current position, with a short fade in. On a handover, the
+    /// old mixer is still playing, so the old voice fades out at the same time.
+    pub fn restart(
+        &mut self,
+        mixer: &MixerHandle,
+        output_channels: u16,
+        cache: &mut ClipCache,
+        fade_in: Duration,
+        handover: bool,
+    ) {
+        if handover {
+            self.mixer.send(MixerCommand::FadeOut(self.id, fade_in));
+        }
+        self.mixer = mixer.clone();
+        let gain = self.last_gain_sent.unwrap_or(self.volume);
+        // A clip still waiting for its start delay keeps waiting for what is left of it
+        let start_delay = self
+            .start_due
+            .duration_since(clock::now())
+            .unwrap_or_default();
+        self.start_voice(cache, output_channels, start_delay, fade_in, gain);
+        if self.paused {
+            self.mixer.send(MixerCommand::Pause(self.id));
         }
+        let tween: Box<dyn Tween<f32> + Send + Sync> = Box::new(Linear);
+        self.current_phase =
+            PlaybackPhase::Attack(Tweener::new(0., 1., fade_in.as_millis(), tween));
     }
 
     pub fn is_completed(&self) -> bool {
-        self.sink.empty()
+        self.status.is_finished()
+    }
+
+    /// Time actually played, not counting any start delay or time spent paused
+    fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.status.frames_played() as f64 / self.mixer.sample_rate() as f64,
+        )
+    }
+
+    /// Time left until the end of the clip (or the end of the current loop, if looping
+    /// forever), if known
+    pub fn remaining(&self) -> Option<Duration> {
+        let d = self.duration?;
+        let source_elapsed = self.source_elapsed;
+        let left = match self.region.play_length(d) {
+            Some(length) => length.saturating_sub(source_elapsed),
+            None => self
+                .region
+                .loop_end
+                .unwrap_or(d)
+                .saturating_sub(self.region.playhead(source_elapsed, d)),
+        };
+        Some(left.div_f32(self.effective_rate()))
+    }
+
+    /// Rate actually applied, including any random pitch variation
+    fn effective_rate(&self) -> f32 {
+        (self.rate * self.rate_variation).clamp(MIN_RATE, MAX_RATE)
     }
 
     pub fn update_progress(&mut self) {
-        let elapsed = self.started.elapsed().unwrap_or(Duration::ZERO)
-            - self.elapsed_to_remove.unwrap_or_default();
+        let elapsed = self.elapsed();
+
+        let since_last_update = elapsed.saturating_sub(self.last_elapsed);
+        self.last_elapsed = elapsed;
+        self.source_elapsed += since_last_update.mul_f32(self.effective_rate());
+
+        if let Some((ramp_start, tween)) = &mut self.rate_ramp {
+            self.rate = tween.move_to(clock::since(*ramp_start).as_millis());
+            if tween.is_finished() {
+                self.rate_ramp = None;
+            }
+            self.rate_control.set(self.effective_rate());
+        }
 
-        // Set volume according to phase...
-        self.current_volume = match &mut self.current_phase {
+        if let Some((ramp_start, cutoff, resonance)) = &mut self.filter_ramp {
+            let ms = clock::since(*ramp_start).as_millis();
+            self.filter.set_cutoff(cutoff.move_to(ms).exp2());
+            self.filter.set_resonance(resonance.move_to(ms));
+            if cutoff.is_finished() && resonance.is_finished() {
+                self.filter_ramp = None;
+            }
+        }
+
+        // Follow the fade envelope according to phase (for reporting only)...
+        self.envelope = match &mut self.current_phase {
             PlaybackPhase::Attack(tween) => tween.move_to(elapsed.as_millis()),
-            PlaybackPhase::Sustain() => self.current_volume,
+            PlaybackPhase::Sustain() => 1.,
             PlaybackPhase::Release(fade_start, tween) => {
-                let elapsed_since_fade_start = fade_start.elapsed().unwrap_or_default();
+                let elapsed_since_fade_start = clock::since(*fade_start);
                 tween.move_to(elapsed_since_fade_start.as_millis())
             }
         };
 
-        self.sink.set_volume(self.current_volume);
+        let gain = self.volume * self.ducking_gain;
+        if self.last_gain_sent != Some(gain) {
+            self.mixer.send(MixerCommand::SetGain(self.id, gain));
+            self.last_gain_sent = Some(gain);
+        }
 
-        // Transition phases automatically in some cases...
-        match &mut self.current_phase {
-            PlaybackPhase::Attack(tween) => {
-                if tween.is_finished() {
-                    self.current_phase = PlaybackPhase::Sustain();
-                }
+        // Transition phases automatically; the mixer removes the voice itself once a
+        // fade out is complete
+        if let PlaybackPhase::Attack(tween) = &self.current_phase {
+            if tween.is_finished() {
+                self.current_phase = PlaybackPhase::Sustain();
             }
-            PlaybackPhase::Release(_fade_start, tween) => {
-                if tween.is_finished() {
-                    self.stop();
-                }
-            }
-            _ => {}
         }
 
-        if let Some(d) = self.duration {
-            let progress = (elapsed.as_millis() % d.as_millis()) as f32 / d.as_millis() as f32;
-            self.last_known_progress = Some(progress);
+        if let Some(d) = self.duration.filter(|d| !d.is_zero()) {
+            let position = self.region.playhead(self.source_elapsed, d);
+            self.last_known_progress = Some(position.as_secs_f32() / d.as_secs_f32());
         }
     }
 
@@ -155,44 +384,117 @@ impl ClipWithSink {
     }
 
     pub fn stop(&self) {
-        self.sink.clear();
+        self.mixer.send(MixerCommand::Stop(self.id));
     }
 
     pub fn pause(&mut self) {
-        self.time_last_paused = Some(SystemTime::now());
-        self.sink.pause();
+        self.paused = true;
+        self.mixer.send(MixerCommand::Pause(self.id));
     }
 
     pub fn resume(&mut self) {
-        if let Some(t) = self.time_last_paused {
-            self.elapsed_to_remove =
-                Some(t.elapsed().unwrap_or_default() + self.elapsed_to_remove.unwrap_or_default());
-            self.time_last_paused = None;
-        }
-        self.sink.play();
+        self.paused = false;
+        self.mixer.send(MixerCommand::Resume(self.id));
     }
 
     pub fn is_paused(&self) -> bool {
-        self.sink.is_paused()
+        self.paused
     }
 
     pub fn fade_out(&mut self, duration: Duration) {
+        self.mixer.send(MixerCommand::FadeOut(self.id, duration));
+
         let tween: Box<dyn Tween<f32> + Send + Sync> = Box::new(Linear);
-        let stored_tweener = Tweener::new(self.current_volume, 0., duration.as_millis(), tween);
+        let stored_tweener = Tweener::new(self.envelope, 0., duration.as_millis(), tween);
+
+        self.current_phase = PlaybackPhase::Release(clock::now(), stored_tweener);
+    }
+
+    /// Change the playback rate, immediately or with a linear ramp
+    pub fn set_rate(&mut self, rate: f32, ramp: Option<Duration>) {
+        match ramp {
+            Some(duration) => {
+                let tween: Box<dyn Tween<f32> + Send + Sync> = Box::new(Linear);
+                let tweener = Tweener::new(self.rate, rate, duration.as_millis(), tween);
+                self.rate_ramp = Some((clock::now(), tweener));
+            }
+            None => {
+                self.rate = rate;
+                self.rate_ramp = None;
+                self.rate_control.set(self.effective_rate());
+            }
+        }
+    }
 
-        self.current_phase = PlaybackPhase::Release(SystemTime::now(), stored_tweener);
+    /// Change the filter type immediately, and cutoff/resonance immediately or with a
+    /// ramp. The cutoff ramps evenly in pitch, rather than frequency.
+    pub fn set_filter(&mut self, update: &FilterUpdate, ramp: Option<Duration>) {
+        match ramp {
+            Some(duration) => {
+                if let Some(kind) = update.kind {
+                    self.filter.set_kind(kind);
+                }
+                let (cutoff, resonance) = (self.filter.cutoff(), self.filter.resonance());
+                let linear = || -> Box<dyn Tween<f32> + Send + Sync> { Box::new(Linear) };
+                self.filter_ramp = Some((
+                    clock::now(),
+                    Tweener::new(
+                        cutoff.log2(),
+                        update.cutoff.unwrap_or(cutoff).log2(),
+                        duration.as_millis(),
+                        linear(),
+                    ),
+                    Tweener::new(
+                        resonance,
+                        update.resonance.unwrap_or(resonance),
+                        duration.as_millis(),
+                        linear(),
+                    ),
+                ));
+            }
+            None => {
+                self.filter_ramp = None;
+                apply_filter_update(&self.filter, update);
+            }
+        }
+    }
+
+    pub fn filter(&self) -> &FilterControl {
+        &self.filter
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Volume including any fade in progress (approximately, since the fade itself is
+    /// applied in the mixer)
     pub fn current_volume(&self) -> f32 {
-        self.current_volume
+        self.volume * self.envelope
     }
 
     pub fn set_volume(&mut self, volume: f32) {
-        self.current_volume = volume;
+        self.volume = volume;
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    pub fn ducking_gain(&self) -> f32 {
+        self.ducking_gain
+    }
+
+    pub fn set_ducking_gain(&mut self, gain: f32) {
+        self.ducking_gain = gain;
     }
 
     pub fn is_looping(&self) -> bool {
@@ -208,6 +510,18 @@ impl ClipWithSink {
     }
 }
 
+fn apply_filter_update(filter: &FilterControl, update: &FilterUpdate) {
+    if let Some(kind) = update.kind {
+        filter.set_kind(kind);
+    }
+    if let Some(cutoff) = update.cutoff {
+        filter.set_cutoff(cutoff);
+    }
+    if let Some(resonance) = update.resonance {
+        filter.set_resonance(resonance);
+    }
+}
+
 fn parse_optional_volume(sample_volume: Option<f32>, override_volume: Option<f32>) -> f32 {
     match override_volume {
         Some(v) => v,
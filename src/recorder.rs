@@ -0,0 +1,187 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::*;
+use rtrb::{Consumer, RingBuffer};
+
+use crate::{
+    clock,
+    mixer::{MixerCommand, MixerHandle},
+    utils::sample_to_i16,
+};
+
+type Writer = WavWriter<BufWriter<File>>;
+
+/// Length of output the tap's ring buffer can hold, if the recording thread falls behind
+const TAP_BUFFER: Duration = Duration::from_secs(2);
+
+/// How often the recording thread writes out whatever the Mixer has copied
+const WRITE_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSettings {
+    /// Maximum length of each file; after this, recording stops (or, if rotating,
+    /// continues in a new file)
+    pub max_duration: Option<Duration>,
+    pub rotate: bool,
+}
+
+/// Writes the final output mix (tapped from the Mixer) to timestamped WAV files. The
+/// writing happens on a separate thread, so the audio thread only has to copy samples
+/// into a ring buffer, allocated here in advance.
+pub struct Recorder {
+    started: SystemTime,
+    thread: JoinHandle<()>,
+}
+
+impl Recorder {
+    pub fn start(
+        dir: &Path,
+        settings: RecordingSettings,
+        mixer: &MixerHandle,
+    ) -> anyhow::Result<Self> {
+        let spec = WavSpec {
+            channels: mixer.channels(),
+            sample_rate: mixer.sample_rate(),
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        fs::create_dir_all(dir)?;
+        // The first file is created here, so that any problem is reported straight away
+        let writer = create_file(dir, spec)?;
+
+        let samples =
+            (TAP_BUFFER.as_secs_f64() * spec.sample_rate as f64) as usize * spec.channels as usize;
+        let (producer, consumer) = RingBuffer::new(samples);
+        let dir = dir.to_path_buf();
+        let thread = thread::spawn(move || {
+            if let Err(e) = write_files(writer, consumer, &dir, spec, &settings) {
+                error!("Recording failed: {}", e);
+            }
+        });
+        mixer.send(MixerCommand::StartTap(producer));
+
+        Ok(Recorder {
+            started: clock::now(),
+            thread,
+        })
+    }
+
+    /// The file is completed (on the recording thread) once the Mixer has let go of
+    /// the tap
+    pub fn stop(self, mixer: &MixerHandle) {
+        mixer.send(MixerCommand::StopTap);
+    }
+
+    /// True if recording has stopped by itself, i.e. reached its maximum duration or failed
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        clock::since(self.started)
+    }
+}
+
+/// Create a new file named after the current (local) time
+fn create_file(dir: &Path, spec: WavSpec) -> anyhow::Result<Writer> {
+    let stem = format!(
+        "soundscape-{}",
+        clock::local_now().format("%Y-%m-%d_%H-%M-%S")
+    );
+    let mut path = dir.join(format!("{stem}.wav"));
+    let mut n = 1;
+    while path.exists() {
+        n += 1;
+        path = dir.join(format!("{stem}-{n}.wav"));
+    }
+    let writer = WavWriter::create(&path, spec)?;
+    info!("Recording to {:?}", path);
+    Ok(writer)
+}
+
+/// Write out the tapped samples until the Mixer lets go of the tap
+fn write_files(
+    mut writer: Writer,
+    mut tap: Consumer<f32>,
+    dir: &Path,
+    spec: WavSpec,
+    settings: &RecordingSettings,
+) -> anyhow::Result<()> {
+    let max_samples = settings
+        .max_duration
+        .map(|d| (d.as_secs_f64() * spec.sample_rate as f64) as u64 * spec.channels as u64);
+    let mut written: u64 = 0;
+    loop {
+        // Checked before reading, so that the last samples copied are still written
+        let finished = tap.is_abandoned();
+        for sample in tap.read_chunk(tap.slots())? {
+            if max_samples.is_some_and(|max| written >= max) {
+                writer.finalize()?;
+                if !settings.rotate {
+                    info!("Recording reached its maximum duration");
+                    return Ok(());
+                }
+                writer = create_file(dir, spec)?;
+                written = 0;
+            }
+            writer.write_sample(sample_to_i16(sample))?;
+            written += 1;
+        }
+        if finished {
+            break;
+        }
+        thread::sleep(WRITE_INTERVAL);
+    }
+    writer.finalize()?;
+    info!("Recording stopped");
+    Ok(())
+}
+
+#[test]
+fn rotates_into_new_files() {
+    let dir = std::env::temp_dir().join(format!("soundscape-recorder-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: 1000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let settings = RecordingSettings {
+        max_duration: Some(Duration::from_millis(400)),
+        rotate: true,
+    };
+    let (mut producer, consumer) = RingBuffer::new(1000);
+    // One second altogether: two full files, and one with the remaining 200ms
+    for _ in 0..1000 {
+        producer.push(0.25).unwrap();
+    }
+    drop(producer);
+    write_files(
+        create_file(&dir, spec).unwrap(),
+        consumer,
+        &dir,
+        spec,
+        &settings,
+    )
+    .unwrap();
+
+    let mut lengths: Vec<u32> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| {
+            hound::WavReader::open(entry.unwrap().path())
+                .unwrap()
+                .duration()
+        })
+        .collect();
+    lengths.sort();
+    assert_eq!(lengths, vec![200, 400, 400]);
+}
@@ -0,0 +1,408 @@
+use std::time::Duration;
+
+use rodio::{
+    queue::{queue, SourcesQueueInput},
+    source::Buffered,
+    Sample, Source,
+};
+
+use crate::dsp::{crossfade::CrossfadeLoop, skip::skip_now};
+
+/// How many times the loop region is played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Loops {
+    /// Play once, from the start offset to the end of the file
+    None,
+    /// Play the loop region this many times, then continue to the end of the file
+    Finite(u32),
+    Infinite,
+}
+
+/// Which parts of the file are played, and how many times. All times are
+/// positions in the file, i.e. before any change of playback rate.
+#[derive(Debug, Clone)]
+pub struct PlaybackRegion {
+    pub start_offset: Duration,
+    pub loop_start: Duration,
+    /// End of the loop region; end of the file if None
+    pub loop_end: Option<Duration>,
+    pub loops: Loops,
+    /// Overlap between the end of one pass of the loop region and the start of the next
+    pub crossfade: Duration,
+}
+
+impl PlaybackRegion {
+    /// Resolve the region from optional values in milliseconds. The loop region defaults
+    /// to the whole file (after the start offset).
+    pub fn new(
+        start_offset: Option<u64>,
+        loop_start: Option<u64>,
+        loop_end: Option<u64>,
+        loop_count: Option<u32>,
+        loop_crossfade: Option<u64>,
+        should_loop: bool,
+    ) -> Self {
+        let start_offset = Duration::from_millis(start_offset.unwrap_or_default());
+        let loop_start = loop_start
+            .map(Duration::from_millis)
+            .unwrap_or(start_offset)
+            .max(start_offset);
+        let loop_end = loop_end
+            .map(Duration::from_millis)
+            .filter(|end| *end > loop_start);
+        let loops = match (loop_count, should_loop) {
+            (Some(0), _) => Loops::None,
+            (Some(count), _) => Loops::Finite(count),
+            (None, true) => Loops::Infinite,
+            (None, false) => Loops::None,
+        };
+        PlaybackRegion {
+            start_offset,
+            loop_start,
+            loop_end,
+            loops,
+            crossfade: Duration::from_millis(loop_crossfade.unwrap_or_default()),
+        }
+    }
+
+    fn loop_length(&self, file_duration: Duration) -> Duration {
+        self.loop_end
+            .unwrap_or(file_duration)
+            .min(file_duration)
+            .saturating_sub(self.loop_start)
+    }
+
+    /// The crossfade actually applied, which is at most half the loop region
+    fn crossfade_length(&self, file_duration: Duration) -> Duration {
+        self.crossfade.min(self.loop_length(file_duration) / 2)
+    }
+
+    /// Time between the start of one pass of the loop region and the start of the next
+    fn pass_period(&self, file_duration: Duration) -> Duration {
+        self.loop_length(file_duration) - self.crossfade_length(file_duration)
+    }
+
+    fn intro_length(&self) -> Duration {
+        match self.loops {
+            Loops::None => Duration::ZERO,
+            _ => self.loop_start - self.start_offset,
+        }
+    }
+
+    /// Total time (in the file's own time) until playback ends; None if it never ends
+    pub fn play_length(&self, file_duration: Duration) -> Option<Duration> {
+        match self.loops {
+            Loops::None => Some(file_duration.saturating_sub(self.start_offset)),
+            Loops::Finite(count) => {
+                let outro = match self.loop_end {
+                    Some(end) => file_duration.saturating_sub(end),
+                    None => Duration::ZERO,
+                };
+                // The last pass plays out in full, without overlapping anything
+                let loops = match count {
+                    0 => Duration::ZERO,
+                    _ => {
+                        self.pass_period(file_duration) * count
+                            + self.crossfade_length(file_duration)
+                    }
+                };
+                Some(self.intro_length() + loops + outro)
+            }
+            Loops::Infinite => None,
+        }
+    }
+
+    /// Position in the file, given the time played so far (in the file's own time)
+    pub fn playhead(&self, elapsed: Duration, file_duration: Duration) -> Duration {
+        if self.loops == Loops::None {
+            return (self.start_offset + elapsed).min(file_duration);
+        }
+        let intro = self.intro_length();
+        if elapsed < intro {
+            return self.start_offset + elapsed;
+        }
+        let in_loops = elapsed - intro;
+        let period = self.pass_period(file_duration);
+        if period.is_zero() {
+            return self.loop_start;
+        }
+        let pass = (in_loops.as_micros() / period.as_micros()) as u32;
+        match self.loops {
+            Loops::Finite(count) if pass >= count => {
+                // Past the start of the last overlap, so playing out the last pass and
+                // then the remainder of the file
+                let after_loops = in_loops - period * count;
+                (self.loop_start + period + after_loops).min(file_duration)
+            }
+            _ => {
+                let in_pass = in_loops.as_micros() % period.as_micros();
+                self.loop_start + Duration::from_micros(in_pass as u64)
+            }
+        }
+    }
+
+    /// Where to skip to in a freshly built source (see [Self::build_source]) so that it
+    /// continues from the given time played, e.g. when moving to another output device.
+    /// An endless loop is wound back to its second pass, so that skipping ahead never
+    /// means running through hours of audio; if the length of its passes cannot be
+    /// known (without the file duration or a loop end), it starts its loop region again.
+    fn resume_point(&self, elapsed: Duration, file_duration: Option<Duration>) -> Duration {
+        if self.loops != Loops::Infinite {
+            return elapsed;
+        }
+        let intro = self.intro_length();
+        let Some(in_loops) = elapsed.checked_sub(intro) else {
+            return elapsed;
+        };
+        let Some(file_duration) = file_duration.or(self.loop_end) else {
+            return intro;
+        };
+        let period = self.pass_period(file_duration);
+        if !period.is_zero() && in_loops >= period * 2 {
+            let in_pass = in_loops.as_micros() % period.as_micros();
+            intro + period + Duration::from_micros(in_pass as u64)
+        } else {
+            elapsed
+        }
+    }
+
+    /// Chain the parts of the source together (intro, loops, remainder) into a single
+    /// source, continuing from the given time played (zero for a new playback). Any
+    /// skipping ahead happens here, on the calling thread, rather than once the voice is
+    /// playing on the audio thread.
+    pub fn build_source<S>(
+        &self,
+        source: S,
+        elapsed: Duration,
+    ) -> Box<dyn Source<Item = S::Item> + Send>
+    where
+        S: Source + Send + 'static,
+        S::Item: Sample + Send + Sync,
+    {
+        let file_duration = source.total_duration();
+        let mut built = self.build_from_start(source);
+        if !elapsed.is_zero() {
+            skip_now(&mut built, self.resume_point(elapsed, file_duration));
+        }
+        built
+    }
+
+    /// The start offset and loop start are skipped here, while building the source,
+    /// so that the audio thread never has to decode anything it will not play
+    fn build_from_start<S>(&self, mut source: S) -> Box<dyn Source<Item = S::Item> + Send>
+    where
+        S: Source + Send + 'static,
+        S::Item: Sample + Send + Sync,
+    {
+        if self.loops == Loops::None {
+            skip_now(&mut source, self.start_offset);
+            return Box::new(source);
+        }
+
+        let file_duration = source.total_duration();
+        let base = source.buffered();
+        let (input, output) = queue::<S::Item>(false);
+
+        if self.loop_start > self.start_offset {
+            let mut intro = base.clone();
+            skip_now(&mut intro, self.start_offset);
+            input.append(intro.take_duration(self.loop_start - self.start_offset));
+        }
+
+        // Clones of a buffered source continue from where it is, so every pass starts at
+        // the loop start without skipping again
+        let mut body = base.clone();
+        skip_now(&mut body, self.loop_start);
+        let crossfade = file_duration
+            .map(|d| self.crossfade_length(d))
+            .filter(|c| !c.is_zero());
+        match (crossfade, file_duration) {
+            (Some(crossfade), Some(file_duration)) => {
+                let pass = self.loop_length(file_duration);
+                let count = match self.loops {
+                    Loops::Finite(count) => Some(count),
+                    _ => None,
+                };
+                input.append(CrossfadeLoop::new(
+                    move || body.clone().take_duration(pass),
+                    pass,
+                    crossfade,
+                    count,
+                ));
+                if let (Some(_), Some(end)) = (count, self.loop_end) {
+                    input.append(Self::outro(base, end));
+                }
+            }
+            _ => self.append_plain_loops(&input, base, body),
+        }
+
+        Box::new(output)
+    }
+
+    /// Loop passes simply follow each other, with no overlap
+    fn append_plain_loops<S>(
+        &self,
+        input: &SourcesQueueInput<S::Item>,
+        base: Buffered<S>,
+        body: Buffered<S>,
+    ) where
+        S: Source + Send + 'static,
+        S::Item: Sample + Send + Sync,
+    {
+        match (self.loops, self.loop_end) {
+            (Loops::Infinite, Some(end)) => {
+                input.append(body.take_duration(end - self.loop_start).repeat_infinite())
+            }
+            (Loops::Infinite, None) => input.append(body.repeat_infinite()),
+            (Loops::Finite(count), Some(end)) => {
+                let body = body.take_duration(end - self.loop_start);
+                for _ in 0..count {
+                    input.append(body.clone());
+                }
+                input.append(Self::outro(base, end));
+            }
+            (Loops::Finite(count), None) => {
+                for _ in 0..count {
+                    input.append(body.clone());
+                }
+            }
+            (Loops::None, _) => {}
+        }
+    }
+
+    /// The remainder of the file after the loop region
+    fn outro<S>(mut base: Buffered<S>, end: Duration) -> Buffered<S>
+    where
+        S: Source,
+        S::Item: Sample,
+    {
+        skip_now(&mut base, end);
+        base
+    }
+}
+
+#[cfg(test)]
+fn ms(value: u64) -> Duration {
+    Duration::from_millis(value)
+}
+
+#[test]
+fn single_play_from_offset() {
+    let region = PlaybackRegion::new(Some(2000), None, None, None, None, false);
+    assert_eq!(region.playhead(ms(1000), ms(10000)), ms(3000));
+    assert_eq!(region.play_length(ms(10000)), Some(ms(8000)));
+}
+
+#[test]
+fn intro_then_infinite_loop() {
+    let region = PlaybackRegion::new(None, Some(2000), Some(6000), None, None, true);
+    assert_eq!(region.playhead(ms(1000), ms(10000)), ms(1000));
+    assert_eq!(region.playhead(ms(2500), ms(10000)), ms(2500));
+    assert_eq!(region.playhead(ms(6500), ms(10000)), ms(2500));
+    assert_eq!(region.play_length(ms(10000)), None);
+}
+
+#[test]
+fn finite_loops_then_remainder() {
+    let region = PlaybackRegion::new(None, Some(2000), Some(6000), Some(2), None, false);
+    assert_eq!(
+        region.play_length(ms(10000)),
+        Some(ms(2000 + 4000 * 2 + 4000))
+    );
+    assert_eq!(region.playhead(ms(9000), ms(10000)), ms(5000));
+    assert_eq!(region.playhead(ms(11000), ms(10000)), ms(7000));
+}
+
+#[test]
+fn whole_file_loop_by_default() {
+    let region = PlaybackRegion::new(None, None, None, None, None, true);
+    assert_eq!(region.playhead(ms(12000), ms(10000)), ms(2000));
+}
+
+#[test]
+fn crossfade_shortens_each_pass() {
+    let region = PlaybackRegion::new(None, None, None, Some(3), Some(1000), false);
+    // Three passes of 10s, overlapping by 1s twice
+    assert_eq!(region.play_length(ms(10000)), Some(ms(28000)));
+    assert_eq!(region.playhead(ms(9500), ms(10000)), ms(500));
+    assert_eq!(region.playhead(ms(27000), ms(10000)), ms(9000));
+}
+
+#[test]
+fn endless_loop_resumes_in_second_pass() {
+    let region = PlaybackRegion::new(None, Some(2000), Some(6000), None, None, true);
+    assert_eq!(region.resume_point(ms(7000), Some(ms(10000))), ms(7000));
+    // Intro, then 25 passes and 1.5s into the next
+    let resume = region.resume_point(ms(2000 + 4000 * 25 + 1500), Some(ms(10000)));
+    assert_eq!(resume, ms(2000 + 4000 + 1500));
+    assert_eq!(region.playhead(resume, ms(10000)), ms(3500));
+    // The loop end is enough to know the length of a pass
+    assert_eq!(
+        region.resume_point(ms(2000 + 4000 * 25 + 1500), None),
+        resume
+    );
+
+    let finite = PlaybackRegion::new(None, Some(2000), Some(6000), Some(30), None, false);
+    assert_eq!(finite.resume_point(ms(50000), Some(ms(10000))), ms(50000));
+}
+
+/// Like a decoder that cannot tell its length (e.g. some MP3 and OGG files)
+#[cfg(test)]
+struct UnknownLength(rodio::buffer::SamplesBuffer<f32>);
+
+#[cfg(test)]
+impl Iterator for UnknownLength {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.next()
+    }
+}
+
+#[cfg(test)]
+impl Source for UnknownLength {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        1000
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+fn numbered_samples(count: usize) -> UnknownLength {
+    let samples = (0..count).map(|i| i as f32).collect::<Vec<_>>();
+    UnknownLength(rodio::buffer::SamplesBuffer::new(1, 1000, samples))
+}
+
+#[test]
+fn resumes_without_known_duration() {
+    let once = PlaybackRegion::new(Some(10), None, None, None, None, false);
+    let mut source = once.build_source(numbered_samples(100), ms(30));
+    assert_eq!(source.next(), Some(40.));
+
+    // Two passes of the whole file, played as far as 30ms into the second
+    let twice = PlaybackRegion::new(None, None, None, Some(2), None, false);
+    let mut source = twice.build_source(numbered_samples(100), ms(130));
+    assert_eq!(source.next(), Some(30.));
+}
+
+#[test]
+fn intro_loops_and_outro_are_cut_at_build_time() {
+    let region = PlaybackRegion::new(Some(10), Some(20), Some(30), Some(2), None, false);
+    let played: Vec<f32> = region.build_source(numbered_samples(50), ms(0)).collect();
+    assert_eq!(played.first(), Some(&10.));
+    // Each pass of the loop region starts at the loop start
+    assert_eq!(played.iter().filter(|s| **s == 20.).count(), 2);
+    let outro: Vec<f32> = (30..50).map(|i| i as f32).collect();
+    assert!(played.ends_with(&outro));
+}
@@ -8,6 +8,8 @@ use std::{
 
 use tether_agent::{PlugDefinition, PlugOptionsBuilder, TetherAgent};
 
+use crate::clock;
+
 pub struct RemoteControl {
     state_output_plug: PlugDefinition,
     events_output_plug: PlugDefinition,
@@ -74,7 +76,7 @@ impl RemoteControl {
             state_send_interval,
             state_max_empty,
             state_send_enabled,
-            last_update_sent: SystemTime::now(), // last_clip_count_sent: None,
+            last_update_sent: clock::now(), // last_clip_count_sent: None,
         }
     }
 }
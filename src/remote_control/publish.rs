@@ -1,11 +1,13 @@
-use std::time::SystemTime;
-
 use log::trace;
 use rmp_serde::to_vec_named;
 use serde::Serialize;
 use tether_agent::TetherAgent;
 
-use crate::playback::{ClipWithSink, PlaybackPhase};
+use crate::{
+    clock,
+    playback::{PlaybackPhase, PlayingClip},
+    tempo::TempoClock,
+};
 
 use super::RemoteControl;
 
@@ -19,10 +21,22 @@ struct ClipPlayingEssentialState {
     looping: bool,
     phase: String,
 }
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TempoState {
+    bpm: f32,
+    bar: u64,
+    beat: u32,
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct SoundscapeStateMessage {
     pub clips: Vec<ClipPlayingEssentialState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<TempoState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -30,11 +44,27 @@ struct SoundscapeStateMessage {
 pub enum SoundscapeEvent {
     ClipStarted(String),
     ClipEnded(String),
+    #[serde(rename_all = "camelCase")]
+    SequenceStep {
+        index: usize,
+        clip_name: String,
+    },
+    Error(String),
+    /// The output device (by name) has gone; playback resumes once it is back
+    DeviceLost(String),
+    /// Playback has resumed on this output device
+    DeviceRestored(String),
 }
 
 impl RemoteControl {
-    pub fn publish_state_if_ready(&mut self, agent: &TetherAgent, clips: &[ClipWithSink]) -> bool {
-        let elapsed = self.last_update_sent.elapsed().unwrap();
+    pub fn publish_state_if_ready(
+        &mut self,
+        agent: &TetherAgent,
+        clips: &[PlayingClip],
+        tempo: Option<&TempoClock>,
+        schedule: Option<&str>,
+    ) -> bool {
+        let elapsed = clock::since(self.last_update_sent);
 
         if elapsed <= self.state_send_interval {
             trace!(
@@ -46,7 +76,7 @@ impl RemoteControl {
         }
 
         trace!("Ready to send state update");
-        self.last_update_sent = SystemTime::now();
+        self.last_update_sent = clock::now();
 
         let clip_states: Vec<ClipPlayingEssentialState> = clips
             .iter()
@@ -67,7 +97,20 @@ impl RemoteControl {
 
         let no_clips_playing = &clip_states.is_empty();
 
-        let state = SoundscapeStateMessage { clips: clip_states };
+        let tempo = tempo.map(|clock| {
+            let (bar, beat) = clock.position();
+            TempoState {
+                bpm: clock.bpm(),
+                bar,
+                beat,
+            }
+        });
+
+        let state = SoundscapeStateMessage {
+            clips: clip_states,
+            tempo,
+            schedule: schedule.map(String::from),
+        };
 
         // Check if we have already sent too many "zero length" states
         if *no_clips_playing {
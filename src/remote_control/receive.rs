@@ -1,9 +1,24 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use ::anyhow::anyhow;
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tether_agent::three_part_topic::TetherOrCustomTopic;
 
-use crate::{playback::PanWithRange, utils::parse_optional_panning};
+use crate::{
+    clock,
+    dsp::{
+        filter::{FilterKind, FilterUpdate},
+        reverb::{check_level, ReverbUpdate},
+    },
+    generative::GenerativeSettings,
+    picker::RandomPickOptions,
+    playback::{PanWithRange, PlaybackOverrides},
+    recorder::RecordingSettings,
+    sequence::SequenceSettings,
+    tempo::Quantize,
+    utils::{optional_ms_to_duration, parse_optional_panning, resolve_rate},
+};
 
 use super::RemoteControl;
 
@@ -13,7 +28,9 @@ type FadeDurationMS = u64;
 pub enum ScenePickMode {
     LoopAll,
     OnceAll,
-    OnceRandomSinglePick,
+    OnceRandomSinglePick(RandomPickOptions),
+    RandomInterval(GenerativeSettings),
+    Sequence(SequenceSettings),
 }
 
 pub enum GlobalControlMode {
@@ -21,21 +38,46 @@ pub enum GlobalControlMode {
     ResumeAll(),
     SilenceAll(),
     MasterVolume(f32),
+    /// BPM, optional beats per bar
+    SetTempo(f32, Option<u32>),
+    /// Cancel scheduled instructions with the given ID, or all if none given
+    CancelScheduled(Option<String>),
+    Reverb(ReverbUpdate),
+    StartRecording(RecordingSettings),
+    StopRecording(),
+    /// Move playback to another output device (name, index or pattern)
+    SetDevice(String),
 }
 
 pub enum Instruction {
-    // Clip name, should_loop, optional volume (override), fade duration, optional panning
+    // Clip name, should_loop, optional volume (override), fade duration, optional panning,
+    // optional quantisation, start offset/loop region overrides
     Add(
         ClipName,
         bool,
         Option<f32>,
         Option<FadeDurationMS>,
         Option<PanWithRange>,
+        Option<Quantize>,
+        PlaybackOverrides,
     ),
     // Clip name, option fade duration
     Remove(ClipName, Option<FadeDurationMS>),
-    Scene(ScenePickMode, Vec<ClipName>, Option<FadeDurationMS>),
+    // Clip name, new playback rate, optional ramp duration
+    SetRate(ClipName, f32, Option<FadeDurationMS>),
+    // Clip name, filter changes, optional ramp duration
+    SetFilter(ClipName, FilterUpdate, Option<FadeDurationMS>),
+    Scene(
+        ScenePickMode,
+        Vec<ClipName>,
+        Option<FadeDurationMS>,
+        Option<Quantize>,
+    ),
     Global(GlobalControlMode),
+    // Time to carry out the instruction, optional ID (for cancelling), the instruction itself
+    Scheduled(SystemTime, Option<String>, Box<Instruction>),
+    // Several clip commands received in one message, each possibly scheduled
+    Cues(Vec<Instruction>),
 }
 
 #[derive(Deserialize, Debug)]
@@ -47,14 +89,48 @@ pub struct SingleClipMessage {
     pub pan_position: Option<f32>,
     pub pan_spread: Option<f32>,
     pub volume: Option<f32>,
+    pub quantize: Option<Quantize>,
+    pub delay: Option<u64>,
+    pub at: Option<u64>,
+    pub schedule_id: Option<String>,
+    pub start_offset: Option<u64>,
+    pub loop_start: Option<u64>,
+    pub loop_end: Option<u64>,
+    pub loop_count: Option<u32>,
+    pub rate: Option<f32>,
+    pub pitch: Option<f32>,
+    pub filter_type: Option<FilterKind>,
+    pub cutoff: Option<f32>,
+    pub resonance: Option<f32>,
+    pub reverb_send: Option<f32>,
 }
 
+/// Several Single Clip Messages sent at once, e.g. a short series of scheduled cues
 #[derive(Deserialize, Debug)]
+pub struct CueListMessage {
+    pub cues: Vec<SingleClipMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SceneMessage {
     pub mode: Option<String>,
     pub clip_names: Vec<ClipName>,
     pub fade_duration: Option<FadeDurationMS>,
+    pub interval_range: Option<[u64; 2]>,
+    pub volume_range: Option<[f32; 2]>,
+    pub pan_range: Option<[f32; 2]>,
+    pub pan_spread: Option<f32>,
+    pub max_overlap: Option<usize>,
+    pub weights: Option<Vec<f32>>,
+    pub avoid_repeat: Option<usize>,
+    pub gap: Option<i64>,
+    pub loop_sequence: Option<bool>,
+    pub shuffle: Option<bool>,
+    pub quantize: Option<Quantize>,
+    pub delay: Option<u64>,
+    pub at: Option<u64>,
+    pub schedule_id: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -62,6 +138,100 @@ pub struct SceneMessage {
 pub struct GlobalMessage {
     pub command: String,
     pub volume: Option<f32>,
+    pub bpm: Option<f32>,
+    pub beats_per_bar: Option<u32>,
+    pub schedule_id: Option<String>,
+    pub size: Option<f32>,
+    pub damping: Option<f32>,
+    pub wet: Option<f32>,
+    pub max_duration: Option<u64>,
+    pub rotate: Option<bool>,
+    pub device: Option<String>,
+}
+
+impl SceneMessage {
+    /// Convert to an Instruction, ignoring any scheduling fields
+    pub fn into_instruction(self) -> anyhow::Result<Instruction> {
+        let pick_options = RandomPickOptions {
+            weights: self.weights,
+            avoid_repeat: self.avoid_repeat,
+        };
+
+        let pick_mode = self.mode.unwrap_or(String::from("loopAll"));
+        match pick_mode.as_str() {
+            "loopAll" => Ok(Instruction::Scene(
+                ScenePickMode::LoopAll,
+                self.clip_names,
+                self.fade_duration,
+                self.quantize,
+            )),
+            "onceAll" => Ok(Instruction::Scene(
+                ScenePickMode::OnceAll,
+                self.clip_names,
+                self.fade_duration,
+                self.quantize,
+            )),
+            "onceRandom" => Ok(Instruction::Scene(
+                ScenePickMode::OnceRandomSinglePick(pick_options),
+                self.clip_names,
+                self.fade_duration,
+                self.quantize,
+            )),
+            "randomInterval" => Ok(Instruction::Scene(
+                ScenePickMode::RandomInterval(GenerativeSettings {
+                    interval_range: self.interval_range,
+                    volume_range: self.volume_range,
+                    pan_range: self.pan_range,
+                    pan_spread: self.pan_spread,
+                    max_overlap: self.max_overlap,
+                    pick: pick_options,
+                }),
+                self.clip_names,
+                self.fade_duration,
+                self.quantize,
+            )),
+            "sequence" => Ok(Instruction::Scene(
+                ScenePickMode::Sequence(SequenceSettings {
+                    gap: self.gap,
+                    loop_sequence: self.loop_sequence.unwrap_or(false),
+                    shuffle: self.shuffle.unwrap_or(false),
+                }),
+                self.clip_names,
+                self.fade_duration,
+                self.quantize,
+            )),
+            _ => Err(anyhow!(
+                "Unrecognised 'pick' option for Scene Message: {}",
+                &pick_mode
+            )),
+        }
+    }
+}
+
+/// Wrap the instruction for later, if either a delay (in milliseconds) or an
+/// absolute time (in milliseconds since the Unix epoch) was requested
+fn schedule_if_requested(
+    instruction: Instruction,
+    delay: Option<u64>,
+    at: Option<u64>,
+    schedule_id: Option<String>,
+) -> anyhow::Result<Instruction> {
+    let due = match (delay, at) {
+        (Some(_), Some(_)) => return Err(anyhow!("Specify either delay or at, not both")),
+        (Some(delay), None) => clock::now() + Duration::from_millis(delay),
+        (None, Some(at)) => UNIX_EPOCH + Duration::from_millis(at),
+        (None, None) => {
+            if schedule_id.is_some() {
+                warn!("Schedule ID given without delay or at; ignored");
+            }
+            return Ok(instruction);
+        }
+    };
+    Ok(Instruction::Scheduled(
+        due,
+        schedule_id,
+        Box::new(instruction),
+    ))
 }
 
 impl RemoteControl {
@@ -71,103 +241,206 @@ impl RemoteControl {
         payload: &[u8],
     ) -> anyhow::Result<Instruction> {
         match plug {
-            TetherOrCustomTopic::Tether(three_part_topic) => match three_part_topic.plug_name() {
-                "clipCommands" => {
-                    let clip_message: Result<SingleClipMessage, rmp_serde::decode::Error> =
-                        rmp_serde::from_slice(payload);
-
-                    if let Ok(parsed) = clip_message {
-                        info!("Parsed Single Clip Message: {parsed:?}");
-
-                        let panning: Option<PanWithRange> =
-                            parse_optional_panning(parsed.pan_position, parsed.pan_spread);
-
-                        match parsed.command.as_str() {
-                            "hit" => Ok(Instruction::Add(
-                                parsed.clip_name,
-                                false,
-                                parsed.volume,
-                                parsed.fade_duration,
-                                panning,
-                            )),
-                            "add" => Ok(Instruction::Add(
-                                parsed.clip_name,
-                                true,
-                                parsed.volume,
-                                parsed.fade_duration,
-                                panning,
-                            )),
-                            "remove" => {
-                                Ok(Instruction::Remove(parsed.clip_name, parsed.fade_duration))
-                            }
-                            _ => Err(anyhow!(
-                                "Unrecognised command for Single Clip Message: {}",
-                                &parsed.command
-                            )),
-                        }
-                    } else {
-                        Err(anyhow!("Error parsing Single Clip Message"))
+            TetherOrCustomTopic::Tether(three_part_topic) => {
+                parse_plug_message(three_part_topic.plug_name(), payload)
+            }
+            TetherOrCustomTopic::Custom(_) => panic!("Not a valid Tether topic"),
+        }
+    }
+}
+
+/// Convert a Single Clip Message to an Instruction, scheduled if requested
+fn clip_instruction(parsed: SingleClipMessage) -> anyhow::Result<Instruction> {
+    let panning: Option<PanWithRange> =
+        parse_optional_panning(parsed.pan_position, parsed.pan_spread);
+    let schedule_id = parsed.schedule_id.clone();
+    let rate = resolve_rate(parsed.rate, parsed.pitch)?;
+    let overrides = PlaybackOverrides {
+        start_offset: parsed.start_offset,
+        loop_start: parsed.loop_start,
+        loop_end: parsed.loop_end,
+        loop_count: parsed.loop_count,
+        rate,
+        filter: FilterUpdate {
+            kind: parsed.filter_type,
+            cutoff: parsed.cutoff,
+            resonance: parsed.resonance,
+        },
+        reverb_send: parsed.reverb_send,
+    };
+    overrides.filter.validate()?;
+    check_level("reverbSend", overrides.reverb_send)?;
+
+    match parsed.command.as_str() {
+        "hit" => Ok(Instruction::Add(
+            parsed.clip_name,
+            false,
+            parsed.volume,
+            parsed.fade_duration,
+            panning,
+            parsed.quantize,
+            overrides,
+        )),
+        "add" => Ok(Instruction::Add(
+            parsed.clip_name,
+            true,
+            parsed.volume,
+            parsed.fade_duration,
+            panning,
+            parsed.quantize,
+            overrides,
+        )),
+        "remove" => Ok(Instruction::Remove(parsed.clip_name, parsed.fade_duration)),
+        "setRate" => match rate {
+            Some(rate) => Ok(Instruction::SetRate(
+                parsed.clip_name,
+                rate,
+                parsed.fade_duration,
+            )),
+            None => Err(anyhow!("setRate requires a rate or pitch value")),
+        },
+        "setFilter" if overrides.filter.is_empty() => Err(anyhow!(
+            "setFilter requires a filterType, cutoff or resonance value"
+        )),
+        "setFilter" => Ok(Instruction::SetFilter(
+            parsed.clip_name,
+            overrides.filter,
+            parsed.fade_duration,
+        )),
+        _ => Err(anyhow!(
+            "Unrecognised command for Single Clip Message: {}",
+            &parsed.command
+        )),
+    }
+    .and_then(|instruction| {
+        schedule_if_requested(instruction, parsed.delay, parsed.at, schedule_id)
+    })
+}
+
+/// Convert a message (MessagePack payload) for the given plug into an Instruction; used
+/// for remote control messages, and for offline render timelines
+pub fn parse_plug_message(plug_name: &str, payload: &[u8]) -> anyhow::Result<Instruction> {
+    match plug_name {
+        "clipCommands" => {
+            // A list of cues is tried first, since it would never parse as a single clip
+            let cue_list: Result<CueListMessage, rmp_serde::decode::Error> =
+                rmp_serde::from_slice(payload);
+            if let Ok(parsed) = cue_list {
+                info!("Parsed Cue List Message: {parsed:?}");
+                let cues = parsed
+                    .cues
+                    .into_iter()
+                    .map(clip_instruction)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                return Ok(Instruction::Cues(cues));
+            }
+
+            let clip_message: Result<SingleClipMessage, rmp_serde::decode::Error> =
+                rmp_serde::from_slice(payload);
+
+            if let Ok(parsed) = clip_message {
+                info!("Parsed Single Clip Message: {parsed:?}");
+                clip_instruction(parsed)
+            } else {
+                Err(anyhow!("Error parsing Single Clip Message"))
+            }
+        }
+        "scenes" => {
+            let scene_message: Result<SceneMessage, rmp_serde::decode::Error> =
+                rmp_serde::from_slice(payload);
+
+            if let Ok(parsed) = scene_message {
+                info!("Parsed Scene Message: {parsed:?}");
+
+                let schedule_id = parsed.schedule_id.clone();
+                let (delay, at) = (parsed.delay, parsed.at);
+
+                parsed.into_instruction().and_then(|instruction| {
+                    schedule_if_requested(instruction, delay, at, schedule_id)
+                })
+            } else {
+                Err(anyhow!("Error parsing Scene Message"))
+            }
+        }
+        "globalControls" => {
+            let global_message: Result<GlobalMessage, rmp_serde::decode::Error> =
+                rmp_serde::from_slice(payload);
+
+            if let Ok(parsed) = global_message {
+                info!("Paused GlobalCommand message: {parsed:?}");
+
+                match parsed.command.as_str() {
+                    "pause" => Ok(Instruction::Global(GlobalControlMode::PauseAll())),
+                    "play" => Ok(Instruction::Global(GlobalControlMode::ResumeAll())),
+                    "silence" => Ok(Instruction::Global(GlobalControlMode::SilenceAll())),
+                    "masterVolume" => Ok(Instruction::Global(GlobalControlMode::MasterVolume(
+                        parsed.volume.unwrap_or_default(),
+                    ))),
+                    "setTempo" => match parsed.bpm {
+                        Some(bpm) if bpm > 0. => Ok(Instruction::Global(
+                            GlobalControlMode::SetTempo(bpm, parsed.beats_per_bar),
+                        )),
+                        _ => Err(anyhow!("setTempo requires a positive bpm value")),
+                    },
+                    "cancelScheduled" => Ok(Instruction::Global(
+                        GlobalControlMode::CancelScheduled(parsed.schedule_id),
+                    )),
+                    "reverb" => {
+                        let update = ReverbUpdate {
+                            size: parsed.size,
+                            damping: parsed.damping,
+                            wet: parsed.wet,
+                        };
+                        update.validate()?;
+                        Ok(Instruction::Global(GlobalControlMode::Reverb(update)))
                     }
-                }
-                "scenes" => {
-                    let scene_message: Result<SceneMessage, rmp_serde::decode::Error> =
-                        rmp_serde::from_slice(payload);
-
-                    if let Ok(parsed) = scene_message {
-                        info!("Parsed Scene Message: {parsed:?}");
-
-                        let pick_mode = parsed.mode.unwrap_or(String::from("loopAll"));
-                        match pick_mode.as_str() {
-                            "loopAll" => Ok(Instruction::Scene(
-                                ScenePickMode::LoopAll,
-                                parsed.clip_names,
-                                parsed.fade_duration,
-                            )),
-                            "onceAll" => Ok(Instruction::Scene(
-                                ScenePickMode::OnceAll,
-                                parsed.clip_names,
-                                parsed.fade_duration,
-                            )),
-                            "onceRandom" => Ok(Instruction::Scene(
-                                ScenePickMode::OnceRandomSinglePick,
-                                parsed.clip_names,
-                                parsed.fade_duration,
-                            )),
-                            _ => Err(anyhow!(
-                                "Unrecognised 'pick' option for Scene Message: {}",
-                                &pick_mode
-                            )),
-                        }
-                    } else {
-                        Err(anyhow!("Error parsing Scene Message"))
+                    "startRecording" if parsed.max_duration == Some(0) => {
+                        Err(anyhow!("startRecording maxDuration must be more than zero"))
                     }
-                }
-                "globalControls" => {
-                    let global_message: Result<GlobalMessage, rmp_serde::decode::Error> =
-                        rmp_serde::from_slice(payload);
-
-                    if let Ok(parsed) = global_message {
-                        info!("Paused GlobalCommand message: {parsed:?}");
-
-                        match parsed.command.as_str() {
-                            "pause" => Ok(Instruction::Global(GlobalControlMode::PauseAll())),
-                            "play" => Ok(Instruction::Global(GlobalControlMode::ResumeAll())),
-                            "silence" => Ok(Instruction::Global(GlobalControlMode::SilenceAll())),
-                            "masterVolume" => Ok(Instruction::Global(
-                                GlobalControlMode::MasterVolume(parsed.volume.unwrap_or_default()),
-                            )),
-                            _ => Err(anyhow!(
-                                "Unrecognised command option for GlobalControls Message: {}",
-                                &parsed.command
-                            )),
+                    "startRecording" => Ok(Instruction::Global(GlobalControlMode::StartRecording(
+                        RecordingSettings {
+                            max_duration: optional_ms_to_duration(parsed.max_duration),
+                            rotate: parsed.rotate.unwrap_or(false),
+                        },
+                    ))),
+                    "stopRecording" => Ok(Instruction::Global(GlobalControlMode::StopRecording())),
+                    "setDevice" => match parsed.device {
+                        Some(device) => {
+                            Ok(Instruction::Global(GlobalControlMode::SetDevice(device)))
                         }
-                    } else {
-                        Err(anyhow!("Failed to parse GlobalCommand message"))
-                    }
+                        None => Err(anyhow!("setDevice requires a device")),
+                    },
+                    _ => Err(anyhow!(
+                        "Unrecognised command option for GlobalControls Message: {}",
+                        &parsed.command
+                    )),
                 }
-                &_ => Err(anyhow!("Unrecognised plug name")),
-            },
-            TetherOrCustomTopic::Custom(_) => panic!("Not a valid Tether topic"),
+            } else {
+                Err(anyhow!("Failed to parse GlobalCommand message"))
+            }
+        }
+        &_ => Err(anyhow!("Unrecognised plug name")),
+    }
+}
+
+#[test]
+fn cue_list_parses_in_one_message() {
+    let message = serde_json::json!({
+        "cues": [
+            { "command": "hit", "clipName": "door" },
+            { "command": "hit", "clipName": "footsteps", "delay": 1200 },
+        ]
+    });
+    let payload = rmp_serde::to_vec_named(&message).unwrap();
+    match parse_plug_message("clipCommands", &payload).unwrap() {
+        Instruction::Cues(cues) => {
+            assert!(matches!(&cues[0], Instruction::Add(name, false, ..) if name == "door"));
+            assert!(matches!(
+                &cues[1],
+                Instruction::Scheduled(_, None, instruction)
+                    if matches!(&**instruction, Instruction::Add(name, ..) if name == "footsteps")
+            ));
         }
+        _ => panic!("Expected a cue list"),
     }
 }
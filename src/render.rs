@@ -0,0 +1,213 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::*;
+use serde::Deserialize;
+
+use crate::{
+    clock,
+    mixer::Mixer,
+    model::Model,
+    output::{AudioOutput, OutputInfo},
+    remote_control::receive::{parse_plug_message, Instruction},
+    settings::Cli,
+    utils::sample_to_i16,
+};
+
+/// A scripted list of messages (as would otherwise arrive via Tether), to render offline
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Timeline {
+    /// Total length of the render, in milliseconds
+    pub duration: u64,
+    pub events: Vec<TimelineEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineEvent {
+    /// Milliseconds from the start of the render
+    pub at: u64,
+    /// "clipCommands", "scenes" or "globalControls"
+    pub plug: String,
+    /// Exactly as it would be sent via Tether
+    pub message: serde_json::Value,
+}
+
+impl Timeline {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to load timeline {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("Failed to parse timeline {:?}", path))
+    }
+
+    /// Parse every event up front (so that any mistakes are found before rendering),
+    /// with the clock set to the time each would be received; returned in time order
+    fn instructions(&self, start: SystemTime) -> anyhow::Result<Vec<(Duration, Instruction)>> {
+        let mut instructions = Vec::new();
+        for (i, event) in self.events.iter().enumerate() {
+            let at = Duration::from_millis(event.at);
+            clock::set_virtual(start + at);
+            let payload = rmp_serde::to_vec_named(&event.message)?;
+            let instruction = parse_plug_message(&event.plug, &payload)
+                .with_context(|| format!("Timeline event #{} ({})", i, event.plug))?;
+            instructions.push((at, instruction));
+        }
+        instructions.sort_by_key(|(at, _)| *at);
+        Ok(instructions)
+    }
+}
+
+/// Render the timeline to a WAV file, as set by the command-line options
+pub fn render_to_file(cli: &Cli, timeline_path: &str) -> anyhow::Result<()> {
+    let timeline = Timeline::load(Path::new(timeline_path))?;
+    let spec = WavSpec {
+        channels: cli.output_channels.unwrap_or(2),
+        sample_rate: cli.render_sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(&cli.render_output, spec)
+        .with_context(|| format!("Failed to create {}", cli.render_output))?;
+    info!(
+        "Rendering {} ms to \"{}\" ({} channels at {} Hz) ...",
+        timeline.duration, cli.render_output, spec.channels, spec.sample_rate
+    );
+    render(cli, &timeline, spec.channels, spec.sample_rate, |sample| {
+        writer
+            .write_sample(sample_to_i16(sample))
+            .map_err(anyhow::Error::from)
+    })?;
+    writer.finalize()?;
+    info!("Render complete");
+    Ok(())
+}
+
+/// Run the Model against a virtual clock, with the Mixer pulled directly (no audio
+/// device), passing every output sample to `write`.
+///
+/// The Model is updated every `--updateInterval` of virtual time, and also exactly at
+/// the time of each timeline event, so that events start on the right sample.
+pub fn render(
+    cli: &Cli,
+    timeline: &Timeline,
+    channels: u16,
+    sample_rate: u32,
+    mut write: impl FnMut(f32) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let start = SystemTime::now();
+    let result = (|| {
+        let instructions = timeline.instructions(start)?;
+        clock::set_virtual(start);
+
+        let (mut mixer, mixer_handle) = Mixer::new(channels, sample_rate);
+        let cli = Cli {
+            tether_disable: true,
+            ..cli.clone()
+        };
+        let output = AudioOutput::offline(OutputInfo {
+            name: String::from("offline render"),
+            channels,
+            sample_rate,
+            buffer_size: None,
+            sample_format: None,
+        });
+        let mut model = Model::new(&cli, mixer_handle, channels, output);
+
+        let tick = Duration::from_millis(cli.update_interval.max(1));
+        let total = Duration::from_millis(timeline.duration);
+        let mut pending = instructions.into_iter().peekable();
+        let mut position = Duration::ZERO;
+        let mut frames_written: u64 = 0;
+
+        while position < total {
+            clock::set_virtual(start + position);
+            while let Some((_, instruction)) = pending.next_if(|(at, _)| *at <= position) {
+                model.receive_instruction(instruction);
+            }
+            model.check_progress();
+            model.update();
+
+            let next_event = pending.peek().map(|(at, _)| *at).unwrap_or(total);
+            position = (position + tick).min(next_event).min(total);
+            let frames_due = (position.as_secs_f64() * sample_rate as f64).round() as u64;
+            let samples = (frames_due - frames_written) * channels as u64;
+            for sample in (&mut mixer).take(samples as usize) {
+                write(sample)?;
+            }
+            frames_written = frames_due;
+        }
+        Ok(())
+    })();
+    clock::use_system();
+    result
+}
+
+#[cfg(test)]
+fn render_test_timeline(timeline: &str) -> Vec<f32> {
+    let dir = std::env::temp_dir().join(format!("soundscape-render-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // 100ms of a constant level, at the same sample rate as the render
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut clip = WavWriter::create(dir.join("level.wav"), spec).unwrap();
+    for _ in 0..800 {
+        clip.write_sample(i16::MAX / 2).unwrap();
+    }
+    clip.finalize().unwrap();
+    let bank = dir.join("bank.json");
+    fs::write(
+        &bank,
+        r#"{ "clips": [{ "name": "level", "path": "level.wav" }] }"#,
+    )
+    .unwrap();
+
+    let cli = <Cli as clap::Parser>::parse_from([
+        "tether-soundscape",
+        bank.to_str().unwrap(),
+        "--tether.disable",
+        "--output.channels",
+        "1",
+        "--render.sampleRate",
+        "8000",
+    ]);
+    let timeline: Timeline = serde_json::from_str(timeline).unwrap();
+    let mut output = Vec::new();
+    render(&cli, &timeline, 1, 8000, |s| {
+        output.push(s);
+        Ok(())
+    })
+    .unwrap();
+    output
+}
+
+#[test]
+fn render_is_sample_accurate_and_repeatable() {
+    let timeline = r#"{
+        "duration": 500,
+        "events": [
+            { "at": 205, "plug": "clipCommands", "message": { "command": "hit", "clipName": "level" } }
+        ]
+    }"#;
+    let output = render_test_timeline(timeline);
+    assert_eq!(output.len(), 4000);
+
+    // Starts exactly at 205ms (sample 1640), with an 8ms fade in, and lasts 100ms
+    assert!(output[..1640].iter().all(|s| *s == 0.));
+    assert!(output[1640] > 0.);
+    assert!((output[1640 + 64] - 0.5).abs() < 0.01);
+    assert!((output[1640 + 799] - 0.5).abs() < 0.01);
+    assert!(output[1640 + 800..].iter().all(|s| *s == 0.));
+
+    assert_eq!(output, render_test_timeline(timeline));
+}
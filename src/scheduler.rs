@@ -0,0 +1,94 @@
+use std::time::SystemTime;
+
+use crate::{clock, remote_control::receive::Instruction};
+
+struct ScheduledInstruction {
+    id: Option<String>,
+    due: SystemTime,
+    instruction: Instruction,
+}
+
+/// Holds instructions (from clipCommands or scenes messages) that should only be
+/// carried out at some later time
+#[derive(Default)]
+pub struct Scheduler {
+    pending: Vec<ScheduledInstruction>,
+}
+
+impl Scheduler {
+    pub fn add(&mut self, due: SystemTime, id: Option<String>, instruction: Instruction) {
+        self.pending.push(ScheduledInstruction {
+            id,
+            due,
+            instruction,
+        });
+        // Keep in order of due time, so that instructions due at the same
+        // update are carried out in the right order
+        self.pending.sort_by_key(|s| s.due);
+    }
+
+    /// Cancel all pending instructions with the given ID, or all pending
+    /// instructions if no ID is given. Returns the number cancelled.
+    pub fn cancel(&mut self, id: Option<&str>) -> usize {
+        let before = self.pending.len();
+        match id {
+            Some(id) => self.pending.retain(|s| s.id.as_deref() != Some(id)),
+            None => self.pending.clear(),
+        }
+        before - self.pending.len()
+    }
+
+    /// Remove and return all instructions that are now due, earliest first
+    pub fn take_due(&mut self) -> Vec<Instruction> {
+        let now = clock::now();
+        let count_due = self.pending.iter().take_while(|s| s.due <= now).count();
+        self.pending
+            .drain(..count_due)
+            .map(|s| s.instruction)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+fn remove_instruction(name: &str) -> Instruction {
+    Instruction::Remove(name.into(), None)
+}
+
+#[test]
+fn only_due_instructions_are_taken() {
+    use std::time::Duration;
+
+    let mut scheduler = Scheduler::default();
+    let now = clock::now();
+    scheduler.add(
+        now + Duration::from_secs(60),
+        None,
+        remove_instruction("later"),
+    );
+    scheduler.add(now, None, remove_instruction("now"));
+    let due = scheduler.take_due();
+    assert_eq!(due.len(), 1);
+    assert!(matches!(&due[0], Instruction::Remove(name, _) if name == "now"));
+    assert_eq!(scheduler.len(), 1);
+}
+
+#[test]
+fn cancel_by_id() {
+    let mut scheduler = Scheduler::default();
+    let now = clock::now();
+    scheduler.add(now, Some("cue1".into()), remove_instruction("a"));
+    scheduler.add(now, Some("cue1".into()), remove_instruction("b"));
+    scheduler.add(now, Some("cue2".into()), remove_instruction("c"));
+    assert_eq!(scheduler.cancel(Some("cue1")), 2);
+    assert_eq!(scheduler.cancel(Some("nothing")), 0);
+    assert_eq!(scheduler.cancel(None), 1);
+    assert!(scheduler.is_empty());
+}
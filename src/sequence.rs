@@ -0,0 +1,210 @@
+use std::time::{Duration, SystemTime};
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{clock, tempo::Quantize};
+
+/// Options for a "sequence" scene, all of which are optional in the Scene Message
+#[derive(Debug, Default, Clone)]
+pub struct SequenceSettings {
+    /// Time in milliseconds between the end of one clip and the start of the next;
+    /// a negative value means the next clip starts *before* the previous one ends
+    pub gap: Option<i64>,
+    /// Start again from the beginning after the last clip, instead of stopping
+    pub loop_sequence: bool,
+    /// Play the clips in a random order (reshuffled on every loop)
+    pub shuffle: bool,
+}
+
+/// A list of clips played back to back, one at a time (apart from any overlap)
+pub struct ActiveSequence {
+    clip_names: Vec<String>,
+    order: Vec<usize>,
+    position: usize,
+    settings: SequenceSettings,
+    fade: Option<Duration>,
+    /// Wait for the next beat or bar before starting each clip
+    quantize: Option<Quantize>,
+    /// ID of the clip currently playing, if any
+    current_clip: Option<usize>,
+    next_start: Option<SystemTime>,
+    /// Number of clips in a row that could not be started
+    failed: usize,
+}
+
+impl ActiveSequence {
+    /// The first clip is started immediately
+    pub fn new(
+        clip_names: Vec<String>,
+        settings: SequenceSettings,
+        fade: Option<Duration>,
+        quantize: Option<Quantize>,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut sequence = ActiveSequence {
+            order: (0..clip_names.len()).collect(),
+            clip_names,
+            position: 0,
+            settings,
+            fade,
+            quantize,
+            current_clip: None,
+            next_start: Some(clock::now()),
+            failed: 0,
+        };
+        if sequence.settings.shuffle {
+            sequence.order.shuffle(rng);
+        }
+        sequence
+    }
+
+    pub fn len(&self) -> usize {
+        self.clip_names.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn fade(&self) -> Option<Duration> {
+        self.fade
+    }
+
+    pub fn quantize(&self) -> Option<Quantize> {
+        self.quantize
+    }
+
+    /// Amount of overlap with the next clip, if the gap is negative
+    pub fn overlap(&self) -> Option<Duration> {
+        match self.settings.gap {
+            Some(gap) if gap < 0 => Some(Duration::from_millis(gap.unsigned_abs())),
+            _ => None,
+        }
+    }
+
+    pub fn current_clip(&self) -> Option<usize> {
+        self.current_clip
+    }
+
+    /// If it is time to start the next clip, returns its position and name
+    pub fn due(&self) -> Option<(usize, &str)> {
+        match self.next_start {
+            Some(t) if clock::now() >= t => self
+                .order
+                .get(self.position)
+                .map(|i| (self.position, self.clip_names[*i].as_str())),
+            _ => None,
+        }
+    }
+
+    /// Called with the ID of the clip just started, or None if it could not be played
+    pub fn on_started(&mut self, clip_id: Option<usize>) {
+        self.current_clip = clip_id;
+        self.next_start = None;
+        match clip_id {
+            Some(_) => self.failed = 0,
+            None => self.failed += 1,
+        }
+    }
+
+    /// True once a whole pass through the sequence has failed to start any clip
+    pub fn is_unplayable(&self) -> bool {
+        self.failed >= self.order.len()
+    }
+
+    /// The current clip has ended (or is about to, when overlapping): schedule the next
+    /// one. Returns false if the sequence has finished, or none of its clips can be played.
+    pub fn advance(&mut self, rng: &mut impl Rng) -> bool {
+        self.current_clip = None;
+        if self.is_unplayable() {
+            return false;
+        }
+        self.position += 1;
+        if self.position >= self.order.len() {
+            if !self.settings.loop_sequence {
+                return false;
+            }
+            self.position = 0;
+            if self.settings.shuffle {
+                self.order.shuffle(rng);
+            }
+        }
+        let gap = match self.settings.gap {
+            Some(gap) if gap > 0 => Duration::from_millis(gap as u64),
+            _ => Duration::ZERO,
+        };
+        self.next_start = Some(clock::now() + gap);
+        true
+    }
+}
+
+#[cfg(test)]
+fn sequence(settings: SequenceSettings) -> ActiveSequence {
+    let names = ["a", "b", "c"].iter().map(|n| n.to_string()).collect();
+    ActiveSequence::new(names, settings, None, None, &mut rand::thread_rng())
+}
+
+#[test]
+fn steps_through_and_stops_at_the_end() {
+    let mut rng = rand::thread_rng();
+    let mut sequence = sequence(SequenceSettings::default());
+    for expected in ["a", "b", "c"] {
+        let (_, name) = sequence.due().unwrap();
+        assert_eq!(name, expected);
+        sequence.on_started(Some(1));
+        assert!(sequence.due().is_none());
+        if expected != "c" {
+            assert!(sequence.advance(&mut rng));
+        }
+    }
+    assert!(!sequence.advance(&mut rng));
+}
+
+#[test]
+fn loops_back_to_the_start() {
+    let mut rng = rand::thread_rng();
+    let mut sequence = sequence(SequenceSettings {
+        loop_sequence: true,
+        ..Default::default()
+    });
+    for _ in 0..3 {
+        sequence.on_started(Some(1));
+        assert!(sequence.advance(&mut rng));
+    }
+    assert_eq!(sequence.due(), Some((0, "a")));
+}
+
+#[test]
+fn positive_gap_delays_the_next_clip() {
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    clock::set_virtual(start);
+    let mut sequence = sequence(SequenceSettings {
+        gap: Some(500),
+        ..Default::default()
+    });
+    sequence.on_started(Some(1));
+    assert!(sequence.advance(&mut rand::thread_rng()));
+    assert!(sequence.due().is_none());
+    clock::set_virtual(start + Duration::from_millis(500));
+    assert_eq!(sequence.due(), Some((1, "b")));
+    clock::use_system();
+}
+
+#[test]
+fn looping_stops_when_no_clip_can_be_played() {
+    let mut rng = rand::thread_rng();
+    let mut sequence = sequence(SequenceSettings {
+        loop_sequence: true,
+        ..Default::default()
+    });
+    sequence.on_started(None);
+    assert!(sequence.advance(&mut rng));
+    sequence.on_started(Some(1));
+    assert!(sequence.advance(&mut rng));
+    for _ in 0..2 {
+        sequence.on_started(None);
+        assert!(sequence.advance(&mut rng));
+    }
+    sequence.on_started(None);
+    assert!(!sequence.advance(&mut rng));
+}
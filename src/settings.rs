@@ -1,6 +1,8 @@
 use clap::Parser;
 
-#[derive(Parser, Debug)]
+use crate::output::{combined::CombinedDeviceSpec, OutputSampleFormat};
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     /// Path to JSON file with clips array; if omitted, a suitable demo
@@ -32,14 +34,92 @@ pub struct Cli {
     #[arg(long = "tether.subscribe.id")]
     pub tether_subscribe_id: Option<String>,
 
-    /// Preferred output device name; use host default device if not supplied
+    /// Preferred output device: part of its name (ignoring case), "#index" as listed by
+    /// --listDevices, "/regex/" or "default". Repeat to give fallbacks, tried in order;
+    /// use host default device if not supplied
     #[arg(long = "output.device")]
-    pub preferred_output_device: Option<String>,
+    pub preferred_output_devices: Vec<String>,
+
+    /// Play through several output devices at once, as one combined range of channels
+    /// (in the order given); each chosen as for --output.device, optionally followed by
+    /// "@" and a delay in milliseconds to keep it aligned with the others, e.g.
+    /// "focusrite@12". Repeat for each device
+    #[arg(
+        long = "output.combine",
+        conflicts_with_all = ["preferred_output_devices", "output_null", "output_file"]
+    )]
+    pub combined_output_devices: Vec<CombinedDeviceSpec>,
 
     /// How many channels to use for output; use max available for the device if omitted
     #[arg(long = "output.channels")]
     pub output_channels: Option<u16>,
 
+    /// Decode every clip into memory when the sample bank loads (rather than only those
+    /// marked "preload"), so that they start without reading from disk
+    #[arg(long = "preload")]
+    pub preload_all: bool,
+
+    /// Maximum memory (in MB) for preloaded clips; least recently played clips are
+    /// evicted beyond this
+    #[arg(long = "preload.budgetMB", default_value_t = 256)]
+    pub preload_budget_mb: usize,
+
+    /// List every audio host and output device, with supported channel counts, sample
+    /// rates and buffer sizes, then exit
+    #[arg(long = "listDevices", alias = "list-devices")]
+    pub list_devices: bool,
+
+    /// Print the device list as JSON (with --listDevices)
+    #[arg(long = "listDevices.json", requires = "list_devices")]
+    pub list_devices_json: bool,
+
+    /// Discard the output mix (in real time) instead of playing it, e.g. on machines
+    /// with no sound card
+    #[arg(long = "output.null", conflicts_with = "output_file")]
+    pub output_null: bool,
+
+    /// Write the output mix (in real time) to this WAV file instead of playing it
+    #[arg(long = "output.file")]
+    pub output_file: Option<String>,
+
+    /// Output sample rate; if omitted, the device default (or 48000 for null or file
+    /// output)
+    #[arg(long = "output.sampleRate")]
+    pub output_sample_rate: Option<u32>,
+
+    /// Output buffer size in frames: smaller for lower latency, larger for stability
+    /// on weak hardware; if omitted, the device default
+    #[arg(long = "output.bufferSize")]
+    pub output_buffer_size: Option<u32>,
+
+    /// Output sample format; if omitted, the device default
+    #[arg(long = "output.sampleFormat", value_enum)]
+    pub output_sample_format: Option<OutputSampleFormat>,
+
+    /// Seed for all random picks (scenes, variants, generative layers), so that
+    /// behaviour is repeatable; if omitted, a different seed is used every time
+    #[arg(long = "seed")]
+    pub seed: Option<u64>,
+
+    /// Render offline to a WAV file instead of playing live, following a JSON timeline
+    /// of messages; needs no audio device (or Tether connection)
+    #[arg(long = "render")]
+    pub render_timeline: Option<String>,
+
+    /// Path of the WAV file to write when rendering
+    #[arg(long = "render.output", default_value_t = String::from("render.wav"))]
+    pub render_output: String,
+
+    /// Sample rate of the WAV file when rendering; the number of channels is set by
+    /// --output.channels (default 2)
+    #[arg(long = "render.sampleRate", default_value_t = 48000)]
+    pub render_sample_rate: u32,
+
+    /// Directory for recordings of the output mix (started by the "startRecording"
+    /// command, or the GUI)
+    #[arg(long = "recording.dir", default_value_t = String::from("recordings"))]
+    pub recording_dir: String,
+
     #[arg(long = "loglevel",default_value_t=String::from("info"))]
     pub log_level: String,
 
@@ -0,0 +1,147 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+
+const DEFAULT_BEATS_PER_BAR: u32 = 4;
+
+/// Tempo and meter, as specified in the Sample Bank JSON
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoSettings {
+    pub bpm: f32,
+    pub beats_per_bar: Option<u32>,
+}
+
+/// Grid point to wait for before starting playback
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Quantize {
+    Beat,
+    Bar,
+}
+
+/// A global clock, counting beats and bars from the moment it was started
+pub struct TempoClock {
+    bpm: f32,
+    beats_per_bar: u32,
+    origin: SystemTime,
+}
+
+impl TempoClock {
+    pub fn new(settings: &TempoSettings) -> Self {
+        TempoClock {
+            bpm: settings.bpm,
+            beats_per_bar: settings
+                .beats_per_bar
+                .unwrap_or(DEFAULT_BEATS_PER_BAR)
+                .max(1),
+            origin: clock::now(),
+        }
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn beats_per_bar(&self) -> u32 {
+        self.beats_per_bar
+    }
+
+    fn beat_duration(&self) -> Duration {
+        Duration::from_secs_f64(60. / self.bpm.max(1.) as f64)
+    }
+
+    fn elapsed(&self) -> Duration {
+        clock::since(self.origin)
+    }
+
+    /// Total beats elapsed since the clock started, including the fraction of the current beat
+    fn beats_elapsed(&self, elapsed: Duration) -> f64 {
+        elapsed.as_secs_f64() / self.beat_duration().as_secs_f64()
+    }
+
+    /// Change the tempo (and optionally the meter), without jumping to a different beat
+    pub fn set_tempo(&mut self, bpm: f32, beats_per_bar: Option<u32>) {
+        let beats_so_far = self.beats_elapsed(self.elapsed());
+        self.bpm = bpm;
+        if let Some(b) = beats_per_bar {
+            self.beats_per_bar = b.max(1);
+        }
+        let rewind = Duration::from_secs_f64(beats_so_far * self.beat_duration().as_secs_f64());
+        self.origin = clock::now() - rewind;
+    }
+
+    /// Current position as (bar, beat), both counting from 1
+    pub fn position(&self) -> (u64, u32) {
+        self.position_at(self.elapsed())
+    }
+
+    fn position_at(&self, elapsed: Duration) -> (u64, u32) {
+        let beats = self.beats_elapsed(elapsed).floor() as u64;
+        let per_bar = self.beats_per_bar as u64;
+        (beats / per_bar + 1, (beats % per_bar) as u32 + 1)
+    }
+
+    /// How long to wait for the next beat or bar
+    pub fn time_until_next(&self, quantize: Quantize) -> Duration {
+        self.time_until_next_at(quantize, self.elapsed())
+    }
+
+    fn time_until_next_at(&self, quantize: Quantize, elapsed: Duration) -> Duration {
+        let beats_per_step = match quantize {
+            Quantize::Beat => 1,
+            Quantize::Bar => self.beats_per_bar,
+        } as f64;
+        let steps = self.beats_elapsed(elapsed) / beats_per_step;
+        let next_step = steps.ceil();
+        let remaining_beats = (next_step - steps) * beats_per_step;
+        // Round to whole microseconds, to avoid floating point noise
+        let micros = remaining_beats * self.beat_duration().as_secs_f64() * 1_000_000.;
+        Duration::from_micros(micros.round() as u64)
+    }
+}
+
+#[cfg(test)]
+fn test_clock() -> TempoClock {
+    TempoClock::new(&TempoSettings {
+        bpm: 120.,
+        beats_per_bar: Some(4),
+    })
+}
+
+#[test]
+fn position_counts_from_one() {
+    let clock = test_clock();
+    assert_eq!(clock.position_at(Duration::ZERO), (1, 1));
+    assert_eq!(clock.position_at(Duration::from_millis(1250)), (1, 3));
+    assert_eq!(clock.position_at(Duration::from_millis(2100)), (2, 1));
+}
+
+#[test]
+fn waits_for_next_beat() {
+    let clock = test_clock();
+    assert_eq!(
+        clock.time_until_next_at(Quantize::Beat, Duration::from_millis(1100)),
+        Duration::from_millis(400)
+    );
+}
+
+#[test]
+fn waits_for_next_bar() {
+    let clock = test_clock();
+    assert_eq!(
+        clock.time_until_next_at(Quantize::Bar, Duration::from_millis(2500)),
+        Duration::from_millis(1500)
+    );
+}
+
+#[test]
+fn on_the_grid_starts_now() {
+    let clock = test_clock();
+    assert_eq!(
+        clock.time_until_next_at(Quantize::Bar, Duration::from_secs(4)),
+        Duration::ZERO
+    );
+}
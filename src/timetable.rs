@@ -0,0 +1,227 @@
+use anyhow::anyhow;
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// A daily time range in local time, e.g. "08:00" to "18:00". If `end` is earlier
+/// than `start`, the range continues past midnight (e.g. "22:00" to "07:00").
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyRange {
+    pub start: String,
+    pub end: String,
+    /// Days on which the range *starts*, e.g. `["sat", "sun"]`; every day if empty
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+/// Play a scene (defined in the Sample Bank) during a daily time range
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleEntry {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub range: DailyRange,
+    pub scene: String,
+    /// Crossfade time in milliseconds, overriding the scene's own fade duration
+    pub fade_duration: Option<u64>,
+}
+
+/// Turn down the whole output mix during a daily time range
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    #[serde(flatten)]
+    pub range: DailyRange,
+    pub max_volume: f32,
+}
+
+impl ScheduleEntry {
+    /// The name to report, i.e. the entry name if given, or the scene name otherwise
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.scene)
+    }
+}
+
+fn parse_time(text: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(text, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+        .map_err(|e| anyhow!("Invalid time \"{text}\": {e}"))
+}
+
+impl DailyRange {
+    /// Check that all times and days can be parsed
+    pub fn validate(&self) -> anyhow::Result<()> {
+        parse_time(&self.start)?;
+        parse_time(&self.end)?;
+        self.weekdays()?;
+        Ok(())
+    }
+
+    fn weekdays(&self) -> anyhow::Result<Vec<Weekday>> {
+        self.days
+            .iter()
+            .map(|d| {
+                d.parse::<Weekday>()
+                    .map_err(|_| anyhow!("Invalid day \"{d}\""))
+            })
+            .collect()
+    }
+
+    fn starts_on(&self, day: Weekday) -> bool {
+        let weekdays = self.weekdays().unwrap_or_default();
+        weekdays.is_empty() || weekdays.contains(&day)
+    }
+
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let (Ok(start), Ok(end)) = (parse_time(&self.start), parse_time(&self.end)) else {
+            return false;
+        };
+        let time = now.time();
+        let today = now.weekday();
+        if start <= end {
+            self.starts_on(today) && time >= start && time < end
+        } else {
+            let yesterday = (now - ChronoDuration::days(1)).weekday();
+            (self.starts_on(today) && time >= start) || (self.starts_on(yesterday) && time < end)
+        }
+    }
+}
+
+/// What should change, after checking the timetable
+pub enum TimetableChange {
+    /// Start the scene for the given entry
+    Enter(ScheduleEntry),
+    /// No entry is active any more; fade out with the previous entry's fade duration
+    Leave(Option<u64>),
+}
+
+/// Keeps track of which schedule entry (if any) is currently active
+pub struct Timetable {
+    entries: Vec<ScheduleEntry>,
+    quiet_hours: Vec<QuietHours>,
+    active: Option<usize>,
+}
+
+impl Timetable {
+    pub fn new(entries: Vec<ScheduleEntry>, quiet_hours: Vec<QuietHours>) -> Self {
+        Timetable {
+            entries,
+            quiet_hours,
+            active: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.quiet_hours.is_empty()
+    }
+
+    pub fn active_entry(&self) -> Option<&ScheduleEntry> {
+        self.active.and_then(|i| self.entries.get(i))
+    }
+
+    /// The first matching entry wins, if ranges overlap
+    pub fn update(&mut self, now: NaiveDateTime) -> Option<TimetableChange> {
+        let current = self.entries.iter().position(|e| e.range.contains(now));
+        if current == self.active {
+            return None;
+        }
+        let previous = self.active_entry().map(|e| e.fade_duration);
+        self.active = current;
+        match self.active_entry() {
+            Some(entry) => Some(TimetableChange::Enter(entry.clone())),
+            None => Some(TimetableChange::Leave(previous.flatten())),
+        }
+    }
+
+    /// The lowest max volume of any quiet hours currently in effect
+    pub fn volume_cap(&self, now: NaiveDateTime) -> Option<f32> {
+        self.quiet_hours
+            .iter()
+            .filter(|q| q.range.contains(now))
+            .map(|q| q.max_volume)
+            .reduce(f32::min)
+    }
+}
+
+#[cfg(test)]
+fn at(day: u32, time: &str) -> NaiveDateTime {
+    // 2024-01-01 was a Monday
+    chrono::NaiveDate::from_ymd_opt(2024, 1, day)
+        .unwrap()
+        .and_time(parse_time(time).unwrap())
+}
+
+#[cfg(test)]
+fn range(start: &str, end: &str, days: &[&str]) -> DailyRange {
+    DailyRange {
+        start: start.into(),
+        end: end.into(),
+        days: days.iter().map(|d| String::from(*d)).collect(),
+    }
+}
+
+#[test]
+fn simple_daytime_range() {
+    let r = range("08:00", "18:00", &[]);
+    assert!(!r.contains(at(1, "07:59")));
+    assert!(r.contains(at(1, "08:00")));
+    assert!(r.contains(at(1, "17:59")));
+    assert!(!r.contains(at(1, "18:00")));
+}
+
+#[test]
+fn range_past_midnight() {
+    let r = range("22:00", "07:00", &[]);
+    assert!(r.contains(at(1, "23:30")));
+    assert!(r.contains(at(2, "06:59")));
+    assert!(!r.contains(at(2, "12:00")));
+}
+
+#[test]
+fn range_on_some_days_only() {
+    // Starts Saturday night, so still active early Sunday morning, but not Monday morning
+    let r = range("22:00", "07:00", &["sat"]);
+    assert!(r.contains(at(6, "23:00")));
+    assert!(r.contains(at(7, "03:00")));
+    assert!(!r.contains(at(1, "03:00")));
+    assert!(!r.contains(at(7, "23:00")));
+}
+
+#[test]
+fn timetable_reports_changes_once() {
+    let entry = ScheduleEntry {
+        name: None,
+        range: range("08:00", "18:00", &[]),
+        scene: "day".into(),
+        fade_duration: Some(5000),
+    };
+    let mut timetable = Timetable::new(vec![entry], Vec::new());
+    assert!(timetable.update(at(1, "07:00")).is_none());
+    assert!(matches!(
+        timetable.update(at(1, "08:00")),
+        Some(TimetableChange::Enter(e)) if e.scene == "day"
+    ));
+    assert!(timetable.update(at(1, "09:00")).is_none());
+    assert!(matches!(
+        timetable.update(at(1, "18:00")),
+        Some(TimetableChange::Leave(Some(5000)))
+    ));
+}
+
+#[test]
+fn quietest_hours_win() {
+    let quiet = vec![
+        QuietHours {
+            range: range("20:00", "08:00", &[]),
+            max_volume: 0.5,
+        },
+        QuietHours {
+            range: range("23:00", "06:00", &[]),
+            max_volume: 0.2,
+        },
+    ];
+    let timetable = Timetable::new(Vec::new(), quiet);
+    assert_eq!(timetable.volume_cap(at(1, "12:00")), None);
+    assert_eq!(timetable.volume_cap(at(1, "21:00")), Some(0.5));
+    assert_eq!(timetable.volume_cap(at(2, "01:00")), Some(0.2));
+}
@@ -1,16 +1,54 @@
 use egui::{Color32, ProgressBar, RichText, Ui};
 
-use crate::model::{ActionQueueItem, Model};
+use crate::{
+    dsp::filter::FilterKind,
+    model::{ActionQueueItem, Model},
+};
 
 pub fn render_clips_section(ui: &mut Ui, model: &mut Model) {
     ui.heading(format!("Playing: x{} clips", model.clips_playing.len()));
 
+    if let Some(layer) = &model.generative_layer {
+        let [min, max] = layer.interval_range();
+        ui.label(format!(
+            "Generative layer: x{} clips, every {:.1}-{:.1}s",
+            layer.clip_names().len(),
+            min as f32 / 1000.,
+            max as f32 / 1000.
+        ));
+    }
+
+    if !model.scheduler.is_empty() {
+        ui.label(format!(
+            "Scheduled: x{} instructions",
+            model.scheduler.len()
+        ));
+    }
+
+    if let Some(sequence) = &model.sequence {
+        ui.label(format!(
+            "Sequence: step {}/{}",
+            sequence.position() + 1,
+            sequence.len()
+        ));
+    }
+
     for clip in model.clips_playing.iter() {
         ui.horizontal(|ui| {
             ui.label(format!("#{}: {}", clip.id(), clip.name()));
             if clip.is_looping() {
                 ui.label("🔁");
             }
+            if clip.ducking_gain() < 1.0 {
+                ui.label(RichText::new("ducked").color(Color32::LIGHT_BLUE));
+            }
+            if clip.rate() != 1.0 {
+                ui.label(format!("x{:.2}", clip.rate()));
+            }
+            let filter = clip.filter();
+            if filter.kind() != FilterKind::Bypass {
+                ui.label(format!("{:?} {:.0} Hz", filter.kind(), filter.cutoff()));
+            }
             if ui.button("🗑").clicked() {
                 model
                     .action_queue
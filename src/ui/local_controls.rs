@@ -22,6 +22,8 @@ pub fn render_local_controls(ui: &mut Ui, model: &mut Model) {
                             None,
                             false,
                             None,
+                            None,
+                            Default::default(),
                         ));
                     }
                     if ui.button("once (fade 2s)").clicked() {
@@ -31,6 +33,8 @@ pub fn render_local_controls(ui: &mut Ui, model: &mut Model) {
                             Some(Duration::from_secs(2)),
                             false,
                             None,
+                            None,
+                            Default::default(),
                         ));
                     }
                     if ui.button("loop").clicked() {
@@ -40,6 +44,8 @@ pub fn render_local_controls(ui: &mut Ui, model: &mut Model) {
                             None,
                             true,
                             None,
+                            None,
+                            Default::default(),
                         ));
                     }
                     if ui.button("loop (fade 5s)").clicked() {
@@ -49,6 +55,8 @@ pub fn render_local_controls(ui: &mut Ui, model: &mut Model) {
                             Some(Duration::from_secs(5)),
                             true,
                             None,
+                            None,
+                            Default::default(),
                         ));
                     }
                     if ui.button("stop").clicked() {
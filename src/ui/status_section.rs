@@ -2,7 +2,10 @@ use std::time::SystemTime;
 
 use egui::{Color32, RichText, Ui};
 
-use crate::model::{MessageStats, Model};
+use crate::{
+    devices::exact_device_spec,
+    model::{MessageStats, Model},
+};
 
 pub fn render_status_section(ui: &mut Ui, model: &mut Model) {
     ui.heading("Status");
@@ -17,12 +20,59 @@ pub fn render_status_section(ui: &mut Ui, model: &mut Model) {
             } else {
                 ui.label(RichText::new("Tether not (yet) connected x").color(Color32::RED));
             }
+        }
+        ui.label(format!("Output {}", model.output.info()));
+        if model.output.is_lost() {
+            ui.label(RichText::new("Output device lost; reconnecting...").color(Color32::RED));
+        }
+        render_device_picker(ui, model);
+        ui.horizontal(|ui| {
+            ui.label("Output channels in use:");
+            ui.label(RichText::new(format!("x{}", model.output_channels_used)).strong());
+        });
+
+        if let Some(entry) = model.timetable.active_entry() {
+            ui.horizontal(|ui| {
+                ui.label("Schedule:");
+                ui.label(RichText::new(entry.label()).strong());
+            });
+        }
+        if let Some(cap) = model.volume_cap {
+            ui.label(
+                RichText::new(format!("Quiet hours: max volume {cap:.2}")).color(Color32::YELLOW),
+            );
+        }
+
+        if let Some(clock) = &model.tempo {
+            let (bar, beat) = clock.position();
             ui.horizontal(|ui| {
-                ui.label("Output channels in use:");
-                ui.label(RichText::new(format!("x{}", model.output_channels_used)).strong());
+                ui.label(format!(
+                    "Tempo: {:.1} BPM, {} beats per bar",
+                    clock.bpm(),
+                    clock.beats_per_bar()
+                ));
+                ui.label(RichText::new(format!("{bar}.{beat}")).strong());
             });
         }
 
+        ui.horizontal(|ui| match model.recording_elapsed() {
+            Some(elapsed) => {
+                if ui.button("⏹ Stop recording").clicked() {
+                    model.stop_recording();
+                }
+                let secs = elapsed.as_secs();
+                ui.label(
+                    RichText::new(format!("REC {:02}:{:02}", secs / 60, secs % 60))
+                        .color(Color32::RED),
+                );
+            }
+            None => {
+                if ui.button("⏺ Record output").clicked() {
+                    model.start_recording(Default::default());
+                }
+            }
+        });
+
         // Message stats
         let MessageStats {
             last_clip_message,
@@ -67,3 +117,30 @@ fn colour_by_elapsed(last_time: Option<SystemTime>) -> Color32 {
         },
     }
 }
+
+/// Choose another output device; playback moves across to it straight away
+fn render_device_picker(ui: &mut Ui, model: &mut Model) {
+    let current = model.output.info().name.clone();
+    let mut chosen = None;
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_label("Device")
+            .selected_text(&current)
+            .show_ui(ui, |ui| {
+                for name in model.available_output_devices() {
+                    if ui.selectable_label(*name == current, name).clicked() {
+                        chosen = Some(name.clone());
+                    }
+                }
+            });
+        if ui
+            .button("⟳")
+            .on_hover_text("Refresh device list")
+            .clicked()
+        {
+            model.refresh_output_devices();
+        }
+    });
+    if let Some(name) = chosen.filter(|name| *name != current) {
+        model.set_output_device(&exact_device_spec(&name));
+    }
+}
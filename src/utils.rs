@@ -1,4 +1,3 @@
-use rand::Rng;
 use std::{ops::Range, time::Duration};
 
 use crate::playback::PanWithRange;
@@ -17,9 +16,9 @@ pub fn parse_optional_panning(position: Option<f32>, spread: Option<f32>) -> Opt
 }
 
 // pub fn get_clip_index_with_name<'a>(
-//     clips: &'a [ClipWithSink],
+//     clips: &'a [PlayingClip],
 //     name: &str,
-// ) -> Option<(usize, &'a ClipWithSink)> {
+// ) -> Option<(usize, &'a PlayingClip)> {
 //     clips
 //         .iter()
 //         .enumerate()
@@ -27,7 +26,7 @@ pub fn parse_optional_panning(position: Option<f32>, spread: Option<f32>) -> Opt
 //         .map(|(index, c)| (index, c))
 // }
 
-// pub fn get_clip_index_with_id(clips: &[ClipWithSink], id: usize) -> Option<(usize, &ClipWithSink)> {
+// pub fn get_clip_index_with_id(clips: &[PlayingClip], id: usize) -> Option<(usize, &PlayingClip)> {
 //     clips
 //         .iter()
 //         .enumerate()
@@ -36,9 +35,9 @@ pub fn parse_optional_panning(position: Option<f32>, spread: Option<f32>) -> Opt
 // }
 
 // pub fn get_clip_index_with_id_mut(
-//     clips: &mut [ClipWithSink],
+//     clips: &mut [PlayingClip],
 //     id: usize,
-// ) -> Option<(usize, &mut ClipWithSink)> {
+// ) -> Option<(usize, &mut PlayingClip)> {
 //     clips
 //         .iter_mut()
 //         .enumerate()
@@ -46,7 +45,7 @@ pub fn parse_optional_panning(position: Option<f32>, spread: Option<f32>) -> Opt
 //         .map(|(index, c)| (index, c))
 // }
 
-// pub fn get_highest_id(clips: &[ClipWithSink]) -> usize {
+// pub fn get_highest_id(clips: &[PlayingClip]) -> usize {
 //     let mut highest_so_far = 0;
 //     for el in clips {
 //         if el.id() >= highest_so_far {
@@ -56,10 +55,44 @@ pub fn parse_optional_panning(position: Option<f32>, spread: Option<f32>) -> Opt
 //     highest_so_far
 // }
 
-pub fn pick_random_clip(clip_names: Vec<String>) -> String {
-    let mut rng = rand::thread_rng();
-    let index: usize = rng.gen_range(0..clip_names.len());
-    clip_names[index].clone()
+/// Convert a decibel value (0 dB = unchanged) to a linear volume multiplier
+pub fn db_to_gain(db: f32) -> f32 {
+    f32::powf(10., db / 20.)
+}
+
+/// Convert a pitch shift in semitones to a playback rate multiplier
+pub fn semitones_to_rate(semitones: f32) -> f32 {
+    f32::powf(2., semitones / 12.)
+}
+
+/// Slowest and fastest playback rates allowed, i.e. four octaves down or up. Beyond
+/// these, the resampler would read far too much of the source for every output sample.
+pub const MIN_RATE: f32 = 1. / 16.;
+pub const MAX_RATE: f32 = 16.;
+
+/// A playback rate may be given either directly, or as a pitch shift in semitones
+pub fn resolve_rate(rate: Option<f32>, pitch: Option<f32>) -> anyhow::Result<Option<f32>> {
+    let rate = match (rate, pitch) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!("Specify either rate or pitch, not both"))
+        }
+        (Some(rate), None) => rate,
+        (None, Some(pitch)) => semitones_to_rate(pitch),
+        (None, None) => return Ok(None),
+    };
+    // Also rejects NaN, which fails every comparison
+    if (MIN_RATE..=MAX_RATE).contains(&rate) {
+        Ok(Some(rate))
+    } else {
+        Err(anyhow::anyhow!(
+            "Rate must be between {MIN_RATE} and {MAX_RATE} (or pitch within 48 semitones), not {rate}"
+        ))
+    }
+}
+
+/// Convert an output sample to 16-bit, for writing to WAV files
+pub fn sample_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1., 1.) * i16::MAX as f32) as i16
 }
 
 pub fn optional_ms_to_duration(ms: Option<u64>) -> Option<Duration> {
@@ -93,7 +126,7 @@ pub fn optional_ms_to_duration(ms: Option<u64>) -> Option<Duration> {
 // /// filter the list to return only the names which need to be *added* (i.e. are
 // /// in the latter list, but not the former)
 // pub fn clips_to_add(
-//     currently_playing: &[ClipWithSink],
+//     currently_playing: &[PlayingClip],
 //     to_play: &[String],
 // ) -> impl Iterator<Item = &String> {
 //     to_play.iter().filter(|candidate| {
@@ -108,7 +141,7 @@ pub fn optional_ms_to_duration(ms: Option<u64>) -> Option<Duration> {
 // /// filter the list to return only the IDs for the clips which need to be *removed*
 // /// (i.e. are in the former list, but not the latter)
 // pub fn clips_to_remove(
-//     currently_playing: &[ClipWithSink],
+//     currently_playing: &[PlayingClip],
 //     should_be_playing: &[String],
 // ) -> Vec<usize> {
 //     let mut ids: Vec<usize> = Vec::new();
@@ -122,3 +155,16 @@ pub fn optional_ms_to_duration(ms: Option<u64>) -> Option<Duration> {
 //     }
 //     ids
 // }
+
+#[test]
+fn unusable_rates_are_rejected() {
+    assert_eq!(resolve_rate(None, Some(12.)).unwrap(), Some(2.));
+    assert_eq!(resolve_rate(Some(0.5), None).unwrap(), Some(0.5));
+    assert!(resolve_rate(Some(f32::NAN), None).is_err());
+    assert!(resolve_rate(Some(f32::INFINITY), None).is_err());
+    assert!(resolve_rate(Some(0.), None).is_err());
+    assert!(resolve_rate(Some(100.), None).is_err());
+    assert!(resolve_rate(None, Some(f32::NAN)).is_err());
+    assert!(resolve_rate(None, Some(2000.)).is_err());
+    assert!(resolve_rate(None, Some(-2000.)).is_err());
+}
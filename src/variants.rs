@@ -0,0 +1,107 @@
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+/// How to choose between multiple file variants of the same clip, each time it is triggered
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum VariantSelection {
+    /// Any variant, every time (repeats are possible)
+    #[default]
+    Random,
+    /// Every variant is used once (in random order) before any is repeated
+    Shuffle,
+    /// Every variant is used in the order listed
+    RoundRobin,
+}
+
+/// Keeps track of which variants have been played, for those selection modes
+/// that need some memory
+#[derive(Debug, Default)]
+pub struct VariantSelector {
+    next_index: usize,
+    shuffle_bag: Vec<usize>,
+    last_picked: Option<usize>,
+}
+
+impl VariantSelector {
+    pub fn pick(&mut self, mode: VariantSelection, count: usize, rng: &mut impl Rng) -> usize {
+        if count <= 1 {
+            return 0;
+        }
+        let index = match mode {
+            VariantSelection::Random => rng.gen_range(0..count),
+            VariantSelection::RoundRobin => {
+                let index = self.next_index % count;
+                self.next_index = index + 1;
+                index
+            }
+            VariantSelection::Shuffle => {
+                if self.shuffle_bag.is_empty() {
+                    self.refill_bag(count, rng);
+                }
+                self.shuffle_bag.pop().unwrap_or_default()
+            }
+        };
+        self.last_picked = Some(index);
+        index
+    }
+
+    /// Bag is popped from the end; make sure the next pick does not repeat
+    /// the last one from the previous bag
+    fn refill_bag(&mut self, count: usize, rng: &mut impl Rng) {
+        let mut bag: Vec<usize> = (0..count).collect();
+        bag.shuffle(rng);
+        if let Some(last) = self.last_picked {
+            if bag.last() == Some(&last) {
+                bag.swap(0, count - 1);
+            }
+        }
+        self.shuffle_bag = bag;
+    }
+}
+
+/// Pick a random value within an optional [min, max] range
+pub fn random_in_range(range: Option<[f32; 2]>, rng: &mut impl Rng) -> Option<f32> {
+    range.map(|[min, max]| {
+        if max > min {
+            rng.gen_range(min..max)
+        } else {
+            min
+        }
+    })
+}
+
+#[test]
+fn round_robin_cycles_in_order() {
+    let mut selector = VariantSelector::default();
+    let mut rng = rand::thread_rng();
+    let picks: Vec<usize> = (0..5)
+        .map(|_| selector.pick(VariantSelection::RoundRobin, 3, &mut rng))
+        .collect();
+    assert_eq!(picks, vec![0, 1, 2, 0, 1]);
+}
+
+#[test]
+fn shuffle_uses_all_before_repeating() {
+    let mut selector = VariantSelector::default();
+    let mut rng = rand::thread_rng();
+    for _ in 0..20 {
+        let mut picks: Vec<usize> = (0..4)
+            .map(|_| selector.pick(VariantSelection::Shuffle, 4, &mut rng))
+            .collect();
+        picks.sort();
+        assert_eq!(picks, vec![0, 1, 2, 3]);
+    }
+}
+
+#[test]
+fn shuffle_never_repeats_across_bags() {
+    let mut selector = VariantSelector::default();
+    let mut rng = rand::thread_rng();
+    let mut last = None;
+    for _ in 0..100 {
+        let pick = selector.pick(VariantSelection::Shuffle, 3, &mut rng);
+        assert_ne!(Some(pick), last);
+        last = Some(pick);
+    }
+}